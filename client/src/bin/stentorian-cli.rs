@@ -0,0 +1,352 @@
+//! Interactive client for poking at a running server. Type `help` at the
+//! prompt for the list of commands. Built only with the `cli` feature:
+//! `cargo run -p stentorian-client --features cli -- --port <port>`.
+use chrono::Local;
+use failure::format_err;
+use futures::sync::mpsc;
+use futures::{future, Future, Stream};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread;
+use stentorian::grammar::Grammar;
+use stentorian_client::*;
+use structopt::StructOpt;
+use tokio_core::reactor::Core;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "stentorian-cli")]
+struct Opt {
+    #[structopt(short = "H", long = "host", default_value = "127.0.0.1")]
+    host: IpAddr,
+    #[structopt(short = "p", long = "port")]
+    port: u16,
+    /// File containing the token to authenticate with
+    #[structopt(long = "token-file", parse(from_os_str))]
+    token_file: Option<PathBuf>,
+}
+
+const HELP: &str = "\
+load <file> [name]         load a command grammar from a JSON file
+activate <id> <rule>       activate a rule of a command grammar
+deactivate <id> <rule>     deactivate a rule of a command grammar
+dictation                  load and activate a dictation grammar
+watch                      load and activate a catchall grammar
+unload <id>                unload a grammar
+grammars                   list the grammars of this connection
+mimic <words...>           recognize words as if they were spoken
+mic [state]                get or set the microphone state
+user                       show the current user
+call <method> [params]     call any method, params given as JSON
+quit                       exit";
+
+// the other handles are only kept so they unload when dropped
+#[allow(dead_code)]
+enum Loaded {
+    Command(CommandGrammar),
+    Dictation(DictationGrammar),
+    Catchall(CatchallGrammar),
+}
+
+type Reply = Box<dyn Future<Item = Option<Value>, Error = failure::Error>>;
+
+struct Repl {
+    client: Client,
+    grammars: Rc<RefCell<HashMap<u64, Loaded>>>,
+}
+
+fn reply<F, T>(f: F) -> Reply
+where
+    F: Future<Item = T, Error = failure::Error> + 'static,
+    T: serde::Serialize,
+{
+    Box::new(f.and_then(|v| Ok(Some(serde_json::to_value(v)?))))
+}
+
+fn fail(message: &str) -> Reply {
+    Box::new(future::err(format_err!("{}", message)))
+}
+
+impl Repl {
+    fn rule(&self, args: &[&str], activate: bool) -> Reply {
+        let (id, rule) = match args {
+            [id, rule] => match id.parse() {
+                Ok(id) => (id, *rule),
+                Err(_) => return fail("grammar id should be a number"),
+            },
+            _ => return fail("expected a grammar id and a rule name"),
+        };
+
+        match self.grammars.borrow().get(&id) {
+            Some(Loaded::Command(g)) if activate => reply(g.rule_activate(rule)),
+            Some(Loaded::Command(g)) => reply(g.rule_deactivate(rule)),
+            Some(_) => fail("not a command grammar"),
+            None => fail("no grammar with that id was loaded from this prompt"),
+        }
+    }
+
+    fn load(&self, args: &[&str]) -> Reply {
+        let (path, name) = match args {
+            [path] => (*path, None),
+            [path, name] => (*path, Some(*name)),
+            _ => return fail("expected a file name and an optional grammar name"),
+        };
+
+        let grammar: Grammar = match fs::read_to_string(path)
+            .map_err(failure::Error::from)
+            .and_then(|s| Ok(serde_json::from_str(&s)?))
+        {
+            Ok(grammar) => grammar,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let grammars = self.grammars.clone();
+        reply(
+            self.client
+                .command_grammar_load(&grammar, name)
+                .map(move |g| {
+                    let id = g.id();
+                    grammars.borrow_mut().insert(id, Loaded::Command(g));
+                    id
+                }),
+        )
+    }
+
+    fn dictation(&self) -> Reply {
+        let grammars = self.grammars.clone();
+        reply(self.client.dictation_grammar_load().and_then(move |g| {
+            let id = g.id();
+            let activated = g.activate();
+            grammars.borrow_mut().insert(id, Loaded::Dictation(g));
+            activated.map(move |()| id)
+        }))
+    }
+
+    fn watch(&self) -> Reply {
+        let grammars = self.grammars.clone();
+        reply(self.client.catchall_grammar_load().and_then(move |g| {
+            let id = g.id();
+            let activated = g.activate();
+            grammars.borrow_mut().insert(id, Loaded::Catchall(g));
+            activated.map(move |()| id)
+        }))
+    }
+
+    fn unload(&self, args: &[&str]) -> Reply {
+        let id: u64 = match args {
+            [id] => match id.parse() {
+                Ok(id) => id,
+                Err(_) => return fail("grammar id should be a number"),
+            },
+            _ => return fail("expected a grammar id"),
+        };
+
+        // dropping the handle unloads the grammar
+        match self.grammars.borrow_mut().remove(&id) {
+            Some(_) => Box::new(future::ok(None)),
+            None => fail("no grammar with that id was loaded from this prompt"),
+        }
+    }
+
+    fn call(&self, args: &[&str]) -> Reply {
+        let (method, params) = match args.split_first() {
+            Some((method, rest)) if rest.is_empty() => (*method, json!([])),
+            Some((method, rest)) => match serde_json::from_str(&rest.join(" ")) {
+                Ok(params) => (*method, params),
+                Err(e) => return Box::new(future::err(e.into())),
+            },
+            None => return fail("expected a method name"),
+        };
+
+        Box::new(self.client.call(method, params).map(Some))
+    }
+
+    fn execute(&self, line: &str) -> Reply {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Box::new(future::ok(None)),
+        };
+
+        match command {
+            "help" => {
+                println!("{}", HELP);
+                Box::new(future::ok(None))
+            }
+            "load" => self.load(args),
+            "activate" => self.rule(args, true),
+            "deactivate" => self.rule(args, false),
+            "dictation" => self.dictation(),
+            "watch" => self.watch(),
+            "unload" => self.unload(args),
+            "grammars" => reply(self.client.list_grammars().map(|grammars| {
+                grammars
+                    .into_iter()
+                    .map(|g| {
+                        json!({
+                            "id": g.id,
+                            "kind": g.kind,
+                            "name": g.name,
+                            "active": g.active,
+                            "active_rules": g.active_rules,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })),
+            "mimic" => {
+                let words: Vec<String> = args.iter().map(|w| w.to_string()).collect();
+                reply(self.client.mimic(&words))
+            }
+            "mic" => match args {
+                [] => reply(self.client.call::<Value>("microphone_get_state", json!([]))),
+                [state] => reply(
+                    self.client
+                        .call::<()>("microphone_set_state", json!([state])),
+                ),
+                _ => fail("expected at most one microphone state"),
+            },
+            "user" => reply(self.client.get_current_user()),
+            "call" => self.call(args),
+            _ => fail("unknown command, type help for a list"),
+        }
+    }
+}
+
+fn timestamp() -> String {
+    Local::now().format("%H:%M:%S%.3f").to_string()
+}
+
+/// Positional params are `[grammar_id, event]`, or `[event]` for
+/// notifications that are not about a grammar. Named params hold the same
+/// fields, along with `seq`, `time` and `pause_time`.
+fn print_notification(n: &Notification) {
+    let (id, event) = match n.params {
+        Value::Array(ref params) => match params.as_slice() {
+            [id, event] => (id.clone(), event.clone()),
+            [event] => (Value::Null, event.clone()),
+            _ => (Value::Null, n.params.clone()),
+        },
+        Value::Object(ref params) => {
+            let mut params = params.clone();
+            for meta in &["seq", "time", "pause_time"] {
+                params.remove(*meta);
+            }
+            let id = params.remove("grammar_id").unwrap_or(Value::Null);
+            let event = match params.len() {
+                1 => params.into_iter().map(|(_, event)| event).next().unwrap(),
+                _ => Value::Object(params),
+            };
+            (id, event)
+        }
+        _ => (Value::Null, n.params.clone()),
+    };
+    let event = serde_json::to_string_pretty(&event).unwrap_or_else(|_| event.to_string());
+
+    if id.is_null() {
+        println!("[{}] {}\n{}", timestamp(), n.method, event);
+    } else {
+        println!("[{}] {} (grammar {})\n{}", timestamp(), n.method, id, event);
+    }
+}
+
+fn print_reply(result: Result<Option<Value>>) {
+    match result {
+        Ok(None) | Ok(Some(Value::Null)) => println!("ok"),
+        Ok(Some(value)) => println!(
+            "{}",
+            serde_json::to_string_pretty(&value).unwrap_or_else(|_| value.to_string())
+        ),
+        Err(e) => match e.downcast_ref::<RpcError>() {
+            Some(RpcError {
+                data: Some(data), ..
+            }) => println!("error: {}\n{}", e, data),
+            _ => println!("error: {}", e),
+        },
+    }
+}
+
+/// Reads lines on a separate thread, since stdin cannot be polled by the
+/// reactor.
+fn read_lines() -> mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = mpsc::unbounded();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        prompt();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            if tx.unbounded_send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+fn prompt() {
+    print!("> ");
+    let _ = io::stdout().flush();
+}
+
+fn run() -> Result<()> {
+    env_logger::init();
+    let options = Opt::from_args();
+
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let addr = SocketAddr::new(options.host, options.port);
+
+    let client = core.run(Client::connect(&addr, &handle))?;
+    println!("connected to {}", addr);
+
+    if let Some(ref path) = options.token_file {
+        let token = fs::read_to_string(path)?;
+        core.run(client.auth(token.trim()))?;
+    }
+
+    handle.spawn(client.monitor().for_each(|n| {
+        print_notification(&n);
+        Ok(())
+    }));
+
+    // keep the registration around so engine notifications keep coming
+    let _registration = core.run(client.engine_register(false))?;
+
+    let repl = Repl {
+        client,
+        grammars: Rc::new(RefCell::new(HashMap::new())),
+    };
+
+    let session = read_lines()
+        .take_while(|line| Ok(line.trim() != "quit"))
+        .for_each(move |line| {
+            repl.execute(&line).then(|result| {
+                print_reply(result);
+                prompt();
+                Ok(())
+            })
+        });
+
+    // the stream only fails if the reader thread panicked
+    core.run(session)
+        .map_err(|()| format_err!("could not read from stdin"))?;
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        println!("{}", e);
+    }
+}
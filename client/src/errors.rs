@@ -0,0 +1,17 @@
+use failure::Fail;
+use serde_json::Value;
+
+pub type Result<T> = ::std::result::Result<T, failure::Error>;
+
+/// An error response sent by the server.
+#[derive(Debug, Fail)]
+#[fail(display = "server returned error {}: {}", code, message)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "connection to the server was closed")]
+pub struct ConnectionClosed;
@@ -0,0 +1,328 @@
+use crate::Client;
+use futures::sync::mpsc;
+use futures::{Async, Future, Poll, Stream};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::marker::PhantomData;
+use stentorian::engine::{GrammarEvent, MicrophoneState};
+
+/// Notification sent to engine registrations, mirroring the server's
+/// `EngineNotification`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EngineNotification {
+    Paused { token: String },
+    MicrophoneStateChanged { state: MicrophoneState },
+    UserChanged { name: Option<String> },
+}
+
+/// What a command grammar recognized: the words and what the grammar's
+/// captures matched, as sent by the server in a `[words, matches]` pair.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "(Vec<String>, Option<Value>)")]
+pub struct CommandRecognition {
+    pub words: Vec<String>,
+    pub matches: Option<Value>,
+}
+
+impl From<(Vec<String>, Option<Value>)> for CommandRecognition {
+    fn from((words, matches): (Vec<String>, Option<Value>)) -> Self {
+        CommandRecognition { words, matches }
+    }
+}
+
+/// Notification sent to a command grammar.
+pub type CommandNotification = GrammarEvent<CommandRecognition>;
+
+/// Notification sent to a select grammar, with the recognized words.
+pub type SelectNotification = GrammarEvent<Vec<String>>;
+
+/// Notification sent to a dictation grammar, with the recognized words.
+pub type DictationNotification = GrammarEvent<Vec<String>>;
+
+/// Notification sent to a catchall grammar, with the recognized words.
+pub type CatchallNotification = GrammarEvent<Vec<String>>;
+
+/// Kinds of notifications that can be subscribed to, mirroring the
+/// server's `EventKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Recognition,
+    Rejection,
+    OtherGrammar,
+    Engine,
+}
+
+/// The notifications for a single grammar or registration, decoded as `T`.
+/// Notifications that do not decode are logged and skipped. The stream ends
+/// when the connection closes.
+pub struct Events<T> {
+    receiver: mpsc::UnboundedReceiver<Value>,
+    marker: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Stream for Events<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        loop {
+            let value = match self.receiver.poll()? {
+                Async::Ready(Some(value)) => value,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            match serde_json::from_value(value) {
+                Ok(event) => return Ok(Async::Ready(Some(event))),
+                Err(e) => warn!("could not decode notification: {}", e),
+            }
+        }
+    }
+}
+
+/// A grammar or registration owned by this client, which is released on
+/// the server once the handle is dropped.
+struct Loaded {
+    client: Client,
+    id: u64,
+    unload: &'static str,
+    receiver: Option<mpsc::UnboundedReceiver<Value>>,
+}
+
+impl Loaded {
+    fn new(client: Client, id: u64, unload: &'static str) -> Self {
+        let receiver = Some(client.subscribe(id));
+
+        Loaded {
+            client,
+            id,
+            unload,
+            receiver,
+        }
+    }
+
+    fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> impl Future<Item = T, Error = failure::Error> {
+        self.client.call(method, params)
+    }
+
+    fn events<T>(&mut self) -> Option<Events<T>> {
+        self.receiver.take().map(|receiver| Events {
+            receiver,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl Drop for Loaded {
+    fn drop(&mut self) {
+        self.client.unsubscribe(self.id);
+        self.client.call_detached(self.unload, json!([self.id]));
+    }
+}
+
+pub struct CommandGrammar(Loaded);
+
+impl CommandGrammar {
+    pub(crate) fn new(client: Client, id: u64) -> Self {
+        CommandGrammar(Loaded::new(client, id, "command_grammar_unload"))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    /// The notifications of this grammar. Only the first call returns a
+    /// stream.
+    pub fn events(&mut self) -> Option<Events<CommandNotification>> {
+        self.0.events()
+    }
+
+    pub fn rule_activate(&self, rule_name: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call(
+            "command_grammar_rule_activate",
+            json!([self.0.id, rule_name]),
+        )
+    }
+
+    pub fn rule_deactivate(
+        &self,
+        rule_name: &str,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call(
+            "command_grammar_rule_deactivate",
+            json!([self.0.id, rule_name]),
+        )
+    }
+
+    pub fn list_append(
+        &self,
+        list_name: &str,
+        word: &str,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call(
+            "command_grammar_list_append",
+            json!([self.0.id, list_name, word]),
+        )
+    }
+
+    pub fn list_remove(
+        &self,
+        list_name: &str,
+        word: &str,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call(
+            "command_grammar_list_remove",
+            json!([self.0.id, list_name, word]),
+        )
+    }
+
+    pub fn list_clear(&self, list_name: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("command_grammar_list_clear", json!([self.0.id, list_name]))
+    }
+}
+
+pub struct SelectGrammar(Loaded);
+
+impl SelectGrammar {
+    pub(crate) fn new(client: Client, id: u64) -> Self {
+        SelectGrammar(Loaded::new(client, id, "select_grammar_unload"))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    pub fn events(&mut self) -> Option<Events<SelectNotification>> {
+        self.0.events()
+    }
+
+    pub fn activate(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call("select_grammar_activate", json!([self.0.id]))
+    }
+
+    pub fn deactivate(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call("select_grammar_deactivate", json!([self.0.id]))
+    }
+
+    pub fn text_set(&self, text: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("select_grammar_text_set", json!([self.0.id, text]))
+    }
+
+    pub fn text_change(
+        &self,
+        start: u32,
+        end: u32,
+        text: &str,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call(
+            "select_grammar_text_change",
+            json!([self.0.id, start, end, text]),
+        )
+    }
+
+    pub fn text_delete(
+        &self,
+        start: u32,
+        end: u32,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("select_grammar_text_delete", json!([self.0.id, start, end]))
+    }
+
+    pub fn text_insert(
+        &self,
+        start: u32,
+        text: &str,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call(
+            "select_grammar_text_insert",
+            json!([self.0.id, start, text]),
+        )
+    }
+
+    pub fn text_get(&self) -> impl Future<Item = String, Error = failure::Error> {
+        self.0.call("select_grammar_text_get", json!([self.0.id]))
+    }
+}
+
+pub struct DictationGrammar(Loaded);
+
+impl DictationGrammar {
+    pub(crate) fn new(client: Client, id: u64) -> Self {
+        DictationGrammar(Loaded::new(client, id, "dictation_grammar_unload"))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    pub fn events(&mut self) -> Option<Events<DictationNotification>> {
+        self.0.events()
+    }
+
+    pub fn activate(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("dictation_grammar_activate", json!([self.0.id]))
+    }
+
+    pub fn deactivate(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("dictation_grammar_deactivate", json!([self.0.id]))
+    }
+
+    pub fn context_set(&self, context: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("dictation_grammar_context_set", json!([self.0.id, context]))
+    }
+}
+
+pub struct CatchallGrammar(Loaded);
+
+impl CatchallGrammar {
+    pub(crate) fn new(client: Client, id: u64) -> Self {
+        CatchallGrammar(Loaded::new(client, id, "catchall_grammar_unload"))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    pub fn events(&mut self) -> Option<Events<CatchallNotification>> {
+        self.0.events()
+    }
+
+    pub fn activate(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.0.call("catchall_grammar_activate", json!([self.0.id]))
+    }
+
+    pub fn deactivate(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.0
+            .call("catchall_grammar_deactivate", json!([self.0.id]))
+    }
+}
+
+pub struct EngineRegistration(Loaded);
+
+impl EngineRegistration {
+    pub(crate) fn new(client: Client, id: u64) -> Self {
+        EngineRegistration(Loaded::new(client, id, "engine_unregister"))
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    pub fn events(&mut self) -> Option<Events<EngineNotification>> {
+        self.0.events()
+    }
+}
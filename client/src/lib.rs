@@ -0,0 +1,482 @@
+//! Client for the JSON-RPC protocol spoken by stentorian-server.
+//!
+//! A `Client` runs on a tokio-core reactor. Loading a grammar gives back a
+//! handle object, which offers the methods for that kind of grammar, a
+//! stream of the notifications for that grammar, and unloads the grammar
+//! when it is dropped.
+mod errors;
+mod handles;
+
+pub use crate::errors::*;
+pub use crate::handles::*;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Sink, Stream};
+use log::{debug, error, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use stentorian::engine::MicrophoneState;
+use stentorian::grammar::Grammar;
+use tokio_codec::{Framed, LinesCodec};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+/// What the server knows about one of this connection's grammars, as
+/// returned by `list_grammars`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrammarInfo {
+    pub id: u64,
+    pub kind: String,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub shared: bool,
+    pub active: bool,
+    pub active_rules: BTreeSet<String>,
+    pub lists: BTreeMap<String, Vec<String>>,
+}
+
+/// What the server said about itself in response to `hello`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerInfo {
+    pub server_version: String,
+    pub protocol_versions: Vec<u64>,
+    pub protocol_version: u64,
+    pub transports: Vec<String>,
+    pub capabilities: Vec<String>,
+}
+
+/// A notification as it came in, before it is routed to a handle.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub method: String,
+    pub params: Value,
+}
+
+type Pending = oneshot::Sender<Result<Value>>;
+
+struct Inner {
+    next_id: Cell<u64>,
+    pending: RefCell<HashMap<u64, Pending>>,
+    subscribers: RefCell<HashMap<u64, mpsc::UnboundedSender<Value>>>,
+    monitors: RefCell<Vec<mpsc::UnboundedSender<Notification>>>,
+    outgoing: mpsc::UnboundedSender<String>,
+}
+
+impl Inner {
+    fn dispatch(&self, line: &str) {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("invalid message from server: {}", e);
+                return;
+            }
+        };
+
+        if let Some(method) = message.get("method").and_then(Value::as_str) {
+            self.dispatch_notification(method, &message["params"]);
+        } else if let Some(id) = message.get("id").and_then(Value::as_u64) {
+            self.dispatch_response(id, &message);
+        } else {
+            warn!("unexpected message from server: {}", line);
+        }
+    }
+
+    fn dispatch_response(&self, id: u64, message: &Value) {
+        let sender = match self.pending.borrow_mut().remove(&id) {
+            Some(sender) => sender,
+            // responses to unloads sent from destructors end up here
+            None => return,
+        };
+
+        let result = match message.get("error") {
+            Some(error) => Err(RpcError {
+                code: error["code"].as_i64().unwrap_or(0),
+                message: error["message"].as_str().unwrap_or("").to_owned(),
+                data: error.get("data").cloned(),
+            }
+            .into()),
+            None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+        };
+
+        let _ = sender.send(result);
+    }
+
+    fn dispatch_notification(&self, method: &str, params: &Value) {
+        let notification = Notification {
+            method: method.to_owned(),
+            params: params.clone(),
+        };
+        self.monitors
+            .borrow_mut()
+            .retain(|monitor| monitor.unbounded_send(notification.clone()).is_ok());
+
+        // protocol version 1 sends positional params, later versions named
+        // ones
+        let (id, event, count) = if params.is_object() {
+            (&params["grammar_id"], &params["event"], &params["count"])
+        } else {
+            (&params[0], &params[1], &params[0])
+        };
+
+        if method == "notifications_dropped" {
+            warn!("server dropped {} notifications", count);
+            return;
+        }
+
+        let id = match id.as_u64() {
+            Some(id) => id,
+            None => {
+                debug!("ignoring notification {}", method);
+                return;
+            }
+        };
+
+        if method == "grammar_reload_failed" {
+            warn!(
+                "server could not reload grammar {}: {}",
+                id, event["message"]
+            );
+            return;
+        }
+
+        let mut subscribers = self.subscribers.borrow_mut();
+        let delivered = match subscribers.get(&id) {
+            Some(subscriber) => subscriber.unbounded_send(event.clone()).is_ok(),
+            None => false,
+        };
+
+        if !delivered {
+            subscribers.remove(&id);
+        }
+    }
+
+    fn close(&self) {
+        for (_, sender) in self.pending.borrow_mut().drain() {
+            let _ = sender.send(Err(ConnectionClosed.into()));
+        }
+
+        self.subscribers.borrow_mut().clear();
+        self.monitors.borrow_mut().clear();
+    }
+
+    fn send(&self, id: u64, method: &str, params: Value) -> Result<()> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        self.outgoing
+            .unbounded_send(request.to_string())
+            .map_err(|_| ConnectionClosed.into())
+    }
+
+    fn new_id(&self) -> u64 {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        id
+    }
+}
+
+/// A connection to a stentorian server. Cloning is cheap, all clones share
+/// the same connection.
+#[derive(Clone)]
+pub struct Client {
+    inner: Rc<Inner>,
+}
+
+impl Client {
+    /// Connects over TCP, using the line-delimited transport.
+    pub fn connect(
+        addr: &SocketAddr,
+        handle: &Handle,
+    ) -> impl Future<Item = Client, Error = failure::Error> {
+        let handle = handle.clone();
+
+        TcpStream::connect(addr, &handle)
+            .from_err()
+            .map(move |sock| Client::from_transport(Framed::new(sock, LinesCodec::new()), &handle))
+    }
+
+    /// Runs the protocol on top of any transport carrying one JSON-RPC
+    /// message per item.
+    pub fn from_transport<T>(transport: T, handle: &Handle) -> Client
+    where
+        T: Stream<Item = String> + Sink<SinkItem = String> + 'static,
+        T::Error: Into<failure::Error>,
+        T::SinkError: Into<failure::Error>,
+    {
+        let (sink, stream) = transport.split();
+        let (outgoing, outgoing_rx) = mpsc::unbounded();
+
+        let inner = Rc::new(Inner {
+            next_id: Cell::new(0),
+            pending: RefCell::new(HashMap::new()),
+            subscribers: RefCell::new(HashMap::new()),
+            monitors: RefCell::new(Vec::new()),
+            outgoing,
+        });
+
+        let sink = sink.sink_map_err(|e| {
+            let e: failure::Error = e.into();
+            error!("could not send to server: {}", e);
+        });
+        handle.spawn(outgoing_rx.forward(sink).map(|_| ()));
+
+        let weak: Weak<Inner> = Rc::downgrade(&inner);
+        let closed = weak.clone();
+        let reader = stream
+            .map_err(|e| {
+                let e: failure::Error = e.into();
+                error!("could not receive from server: {}", e);
+            })
+            .for_each(move |line| match weak.upgrade() {
+                Some(inner) => {
+                    inner.dispatch(&line);
+                    Ok(())
+                }
+                // every client is gone, so nobody is listening any more
+                None => Err(()),
+            })
+            .then(move |_| {
+                if let Some(inner) = closed.upgrade() {
+                    inner.close();
+                }
+                Ok(())
+            });
+        handle.spawn(reader);
+
+        Client { inner }
+    }
+
+    /// Calls `method` and decodes its result.
+    pub fn call<T>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> impl Future<Item = T, Error = failure::Error>
+    where
+        T: DeserializeOwned,
+    {
+        let id = self.inner.new_id();
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.borrow_mut().insert(id, tx);
+
+        future::result(self.inner.send(id, method, params))
+            .and_then(move |()| rx.map_err(|_| failure::Error::from(ConnectionClosed)))
+            .and_then(|result| result)
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+    }
+
+    /// Calls `method` without waiting for the response.
+    fn call_detached(&self, method: &str, params: Value) {
+        let id = self.inner.new_id();
+        if let Err(e) = self.inner.send(id, method, params) {
+            debug!("could not call {}: {}", method, e);
+        }
+    }
+
+    fn subscribe(&self, id: u64) -> mpsc::UnboundedReceiver<Value> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.subscribers.borrow_mut().insert(id, tx);
+        rx
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.inner.subscribers.borrow_mut().remove(&id);
+    }
+
+    /// Every notification received from now on, including those for
+    /// grammars this client has no handle for.
+    pub fn monitor(&self) -> mpsc::UnboundedReceiver<Notification> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.monitors.borrow_mut().push(tx);
+        rx
+    }
+
+    pub fn auth(&self, token: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("auth", json!([token]))
+    }
+
+    pub fn command_grammar_load(
+        &self,
+        grammar: &Grammar,
+        name: Option<&str>,
+    ) -> impl Future<Item = CommandGrammar, Error = failure::Error> {
+        let client = self.clone();
+        self.call("command_grammar_load", json!([grammar, name]))
+            .map(move |id| CommandGrammar::new(client, id))
+    }
+
+    /// Loads `grammar` for every connection to share under `name`, or
+    /// attaches to it if it is already loaded. It stays loaded until every
+    /// connection has unloaded it.
+    pub fn command_grammar_load_shared(
+        &self,
+        grammar: &Grammar,
+        name: &str,
+    ) -> impl Future<Item = CommandGrammar, Error = failure::Error> {
+        let client = self.clone();
+        self.call("command_grammar_load_shared", json!([grammar, name]))
+            .map(move |id| CommandGrammar::new(client, id))
+    }
+
+    pub fn command_grammar_attach(
+        &self,
+        name: &str,
+    ) -> impl Future<Item = CommandGrammar, Error = failure::Error> {
+        let client = self.clone();
+        self.call("command_grammar_attach", json!([name]))
+            .map(move |id| CommandGrammar::new(client, id))
+    }
+
+    pub fn command_grammar_list_shared(
+        &self,
+    ) -> impl Future<Item = Vec<String>, Error = failure::Error> {
+        self.call("command_grammar_list_shared", json!([]))
+    }
+
+    pub fn select_grammar_load(
+        &self,
+        select_words: &[String],
+        through_words: &[String],
+    ) -> impl Future<Item = SelectGrammar, Error = failure::Error> {
+        let client = self.clone();
+        self.call("select_grammar_load", json!([select_words, through_words]))
+            .map(move |id| SelectGrammar::new(client, id))
+    }
+
+    pub fn dictation_grammar_load(
+        &self,
+    ) -> impl Future<Item = DictationGrammar, Error = failure::Error> {
+        let client = self.clone();
+        self.call("dictation_grammar_load", json!([]))
+            .map(move |id| DictationGrammar::new(client, id))
+    }
+
+    pub fn catchall_grammar_load(
+        &self,
+    ) -> impl Future<Item = CatchallGrammar, Error = failure::Error> {
+        let client = self.clone();
+        self.call("catchall_grammar_load", json!([]))
+            .map(move |id| CatchallGrammar::new(client, id))
+    }
+
+    /// With `hold_pauses`, the engine waits for `engine_resume` after every
+    /// pause before it starts recognizing, up to the server's timeout.
+    pub fn engine_register(
+        &self,
+        hold_pauses: bool,
+    ) -> impl Future<Item = EngineRegistration, Error = failure::Error> {
+        let client = self.clone();
+        self.call("engine_register", json!([hold_pauses]))
+            .map(move |id| EngineRegistration::new(client, id))
+    }
+
+    pub fn engine_resume(&self, token: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("engine_resume", json!([token]))
+    }
+
+    pub fn microphone_set_state(
+        &self,
+        state: MicrophoneState,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("microphone_set_state", json!([state]))
+    }
+
+    pub fn microphone_get_state(
+        &self,
+    ) -> impl Future<Item = MicrophoneState, Error = failure::Error> {
+        self.call("microphone_get_state", json!([]))
+    }
+
+    pub fn get_current_user(&self) -> impl Future<Item = Option<String>, Error = failure::Error> {
+        self.call("get_current_user", json!([]))
+    }
+
+    pub fn mimic(&self, words: &[String]) -> impl Future<Item = bool, Error = failure::Error> {
+        self.call("engine_mimic", json!([words]))
+    }
+
+    /// Asks the server to shut down, which it only does if it was started
+    /// with shutdown through the API enabled.
+    pub fn server_shutdown(&self) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("server_shutdown", json!([]))
+    }
+
+    /// One of `connecting`, `connected` or `disconnected`.
+    pub fn engine_status(&self) -> impl Future<Item = String, Error = failure::Error> {
+        self.call("engine_status", json!([]))
+    }
+
+    pub fn session_token(&self) -> impl Future<Item = String, Error = failure::Error> {
+        self.call("session_token", json!([]))
+    }
+
+    /// Takes over a session left behind by an earlier connection. Handles
+    /// from that connection are not carried over. This has to happen before
+    /// anything is loaded on this connection.
+    pub fn session_resume(&self, token: &str) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("session_resume", json!([token]))
+    }
+
+    /// Asks the server what it supports, switching to `protocol_version`
+    /// first if one is given. This works before `auth`.
+    pub fn hello(
+        &self,
+        protocol_version: Option<u64>,
+    ) -> impl Future<Item = ServerInfo, Error = failure::Error> {
+        self.call("hello", json!([protocol_version]))
+    }
+
+    /// Switches the connection to another protocol version. From version 2
+    /// on, notifications come with named params.
+    pub fn protocol_version(&self, version: u64) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("protocol_version", json!([version]))
+    }
+
+    /// Starts sending notifications of `kinds` about `grammar_id` again,
+    /// or of every kind if `kinds` is `None`.
+    pub fn events_subscribe(
+        &self,
+        grammar_id: u64,
+        kinds: Option<&[EventKind]>,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("subscribe", json!([grammar_id, kinds]))
+    }
+
+    /// Stops sending notifications of `kinds` about `grammar_id`, or of
+    /// every kind if `kinds` is `None`.
+    pub fn events_unsubscribe(
+        &self,
+        grammar_id: u64,
+        kinds: Option<&[EventKind]>,
+    ) -> impl Future<Item = (), Error = failure::Error> {
+        self.call("unsubscribe", json!([grammar_id, kinds]))
+    }
+
+    pub fn list_grammars(&self) -> impl Future<Item = Vec<GrammarInfo>, Error = failure::Error> {
+        self.call("list_grammars", json!([]))
+    }
+
+    pub fn grammar_get(
+        &self,
+        grammar_id: u64,
+    ) -> impl Future<Item = Grammar, Error = failure::Error> {
+        self.call("grammar_get", json!([grammar_id]))
+    }
+
+    pub fn list_get(
+        &self,
+        grammar_id: u64,
+        list_name: &str,
+    ) -> impl Future<Item = Vec<String>, Error = failure::Error> {
+        self.call("list_get", json!([grammar_id, list_name]))
+    }
+}
@@ -0,0 +1,188 @@
+use crate::errors::*;
+use crate::rpc::RpcAuth;
+use jsonrpc_core::futures::future::{self, Either};
+use jsonrpc_core::middleware::Middleware;
+use jsonrpc_core::{Call, Error as RpcError, FutureOutput, FutureResponse, Output};
+use log::{info, warn};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The shared secret clients have to present before they can use the API.
+pub struct Authenticator {
+    token: Option<String>,
+}
+
+impl Authenticator {
+    /// An authenticator that lets every connection through.
+    pub fn disabled() -> Self {
+        Authenticator { token: None }
+    }
+
+    /// Reads the token from `path`. Surrounding whitespace is ignored, so
+    /// the file may end with a newline.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let token = contents.trim();
+
+        if token.is_empty() {
+            return Err(ErrorKind::InvalidConfig {
+                reason: "token file is empty".to_owned(),
+            }
+            .into());
+        }
+
+        Ok(Authenticator {
+            token: Some(token.to_owned()),
+        })
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.token.is_some()
+    }
+
+    fn verify(&self, candidate: &str) -> bool {
+        match self.token {
+            Some(ref token) => constant_time_eq(token.as_bytes(), candidate.as_bytes()),
+            None => true,
+        }
+    }
+}
+
+/// Compares without bailing out at the first difference, so the time taken
+/// does not reveal how much of the token was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+/// Authentication progress of a single connection.
+pub struct AuthState {
+    authenticator: Arc<Authenticator>,
+    peer: SocketAddr,
+    authenticated: AtomicBool,
+    rejected: AtomicBool,
+    on_authenticated: Mutex<Option<Hook>>,
+}
+
+impl AuthState {
+    pub fn new(authenticator: Arc<Authenticator>, peer: SocketAddr) -> Arc<Self> {
+        let authenticated = !authenticator.is_required();
+
+        Arc::new(AuthState {
+            authenticator,
+            peer,
+            authenticated: AtomicBool::new(authenticated),
+            rejected: AtomicBool::new(false),
+            on_authenticated: Mutex::new(None),
+        })
+    }
+
+    /// Runs `f` once the connection has authenticated, which may be right
+    /// away.
+    pub fn when_authenticated<F: FnOnce() + Send + 'static>(&self, f: F) {
+        if self.is_authenticated() {
+            f();
+        } else {
+            *self.hook() = Some(Box::new(f));
+        }
+    }
+
+    fn hook(&self) -> MutexGuard<Option<Hook>> {
+        self.on_authenticated
+            .lock()
+            .expect("attempt to lock poisoned mutex")
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::SeqCst)
+    }
+
+    /// Whether the connection should be dropped once the pending responses
+    /// have been sent.
+    pub fn is_rejected(&self) -> bool {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    fn reject(&self) {
+        self.rejected.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct RpcAuthImpl(pub Arc<AuthState>);
+
+impl RpcAuth for RpcAuthImpl {
+    fn auth(&self, token: String) -> Result<()> {
+        let state = &self.0;
+
+        if state.authenticator.verify(&token) {
+            info!("client {} authenticated", state.peer);
+            state.authenticated.store(true, Ordering::SeqCst);
+
+            let hook = state.hook().take();
+            if let Some(hook) = hook {
+                hook();
+            }
+            Ok(())
+        } else {
+            warn!("failed authentication attempt from {}", state.peer);
+            state.reject();
+            Err(ErrorKind::AuthenticationFailed.into())
+        }
+    }
+}
+
+/// Refuses every call except `auth` and `hello` until the connection has
+/// authenticated.
+/// A refused call also causes the connection to be dropped.
+pub struct AuthMiddleware(pub Arc<AuthState>);
+
+impl Middleware<()> for AuthMiddleware {
+    type Future = FutureResponse;
+    type CallFuture = FutureOutput;
+
+    fn on_call<F, X>(&self, call: Call, meta: (), next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, ()) -> X + Send + Sync,
+        X: future::Future<Item = Option<Output>, Error = ()> + Send + 'static,
+    {
+        let method = match call {
+            Call::MethodCall(ref c) => Some(c.method.as_str()),
+            Call::Notification(ref n) => Some(n.method.as_str()),
+            Call::Invalid { .. } => None,
+        };
+
+        // clients need `hello` to find out whether they have to authenticate
+        if self.0.is_authenticated() || method == Some("auth") || method == Some("hello") {
+            return Either::B(next(call, meta));
+        }
+
+        warn!(
+            "client {} called {} before authenticating",
+            self.0.peer,
+            method.unwrap_or("<invalid>")
+        );
+        self.0.reject();
+
+        let output = match call {
+            Call::MethodCall(c) => {
+                let error = RpcError::from(MyError::from(ErrorKind::NotAuthenticated));
+                Some(Output::from(Err(error), c.id, c.jsonrpc))
+            }
+            _ => None,
+        };
+
+        Either::A(Box::new(future::ok(output)))
+    }
+}
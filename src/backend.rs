@@ -0,0 +1,148 @@
+use crate::errors::{MyError, Result};
+use stentorian::engine::{
+    CatchallGrammarEvent, CommandGrammarEvent, DictationGrammarEvent, MicrophoneState,
+    SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+
+/// Who a grammar is loaded for, as numbered in a recording: the connection
+/// that opened the session loading it, and the id it has there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label {
+    pub connection: u64,
+    pub id: u64,
+}
+
+/// Told why a grammar was lost, see `when_lost`.
+pub type LostHook = Box<dyn Fn(&MyError) + Send + Sync>;
+
+pub trait CommandControl: Send + 'static {
+    fn rule_activate(&self, name: &str) -> Result<()>;
+    fn rule_deactivate(&self, name: &str) -> Result<()>;
+    fn list_append(&self, name: &str, word: &str) -> Result<()>;
+    fn list_remove(&self, name: &str, word: &str) -> Result<()>;
+    fn list_clear(&self, name: &str) -> Result<()>;
+
+    /// Calls `f` with the reason if the grammar cannot be loaded again
+    /// after the engine restarts.
+    fn when_lost(&self, _f: LostHook) {}
+}
+
+pub trait SelectControl: Send + 'static {
+    fn activate(&self) -> Result<()>;
+    fn deactivate(&self) -> Result<()>;
+    fn text_set(&self, text: &str) -> Result<()>;
+    fn text_change(&self, start: u32, stop: u32, text: &str) -> Result<()>;
+    fn text_delete(&self, start: u32, stop: u32) -> Result<()>;
+    fn text_insert(&self, start: u32, text: &str) -> Result<()>;
+    fn text_get(&self) -> Result<String>;
+
+    /// Calls `f` with the reason if the grammar cannot be loaded again
+    /// after the engine restarts.
+    fn when_lost(&self, _f: LostHook) {}
+}
+
+pub trait DictationControl: Send + 'static {
+    fn activate(&self) -> Result<()>;
+    fn deactivate(&self) -> Result<()>;
+    fn context_set(&self, context: &str) -> Result<()>;
+
+    /// Calls `f` with the reason if the grammar cannot be loaded again
+    /// after the engine restarts.
+    fn when_lost(&self, _f: LostHook) {}
+}
+
+pub trait CatchallControl: Send + 'static {
+    fn activate(&self) -> Result<()>;
+    fn deactivate(&self) -> Result<()>;
+
+    /// Calls `f` with the reason if the grammar cannot be loaded again
+    /// after the engine restarts.
+    fn when_lost(&self, _f: LostHook) {}
+}
+
+/// What an engine tells its registrations, like stentorian's
+/// `EngineEvent`, except that every backend has its own pause cookies.
+#[derive(Debug, Clone)]
+pub enum EngineEvent<C> {
+    /// The engine waits for `resume` with the cookie before it starts
+    /// recognizing.
+    Paused(C),
+    MicrophoneState,
+    UserChanged,
+}
+
+/// The operations the RPC layer needs from a speech engine. Grammars and
+/// registrations stay loaded for as long as the returned control is alive.
+/// Grammars are loaded with the `Label` of whoever asked for them, if the
+/// server is recording.
+pub trait Backend: Send + Sync + 'static {
+    type CommandControl: CommandControl;
+    type SelectControl: SelectControl;
+    type DictationControl: DictationControl;
+    type CatchallControl: CatchallControl;
+    type Registration: Send + 'static;
+    type PauseCookie: Send + 'static;
+
+    fn command_grammar_load<F>(
+        &self,
+        grammar: &Grammar,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CommandControl>
+    where
+        F: Fn(CommandGrammarEvent) + Sync + Send + 'static;
+
+    fn select_grammar_load<F>(
+        &self,
+        select_words: &[String],
+        through_words: &[String],
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::SelectControl>
+    where
+        F: Fn(SelectGrammarEvent) + Sync + Send + 'static;
+
+    fn dictation_grammar_load<F>(
+        &self,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::DictationControl>
+    where
+        F: Fn(DictationGrammarEvent) + Sync + Send + 'static;
+
+    fn catchall_grammar_load<F>(
+        &self,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CatchallControl>
+    where
+        F: Fn(CatchallGrammarEvent) + Sync + Send + 'static;
+
+    fn register<F>(&self, callback: F) -> Result<Self::Registration>
+    where
+        F: Fn(EngineEvent<Self::PauseCookie>) + Sync + Send + 'static;
+
+    fn resume(&self, cookie: Self::PauseCookie) -> Result<()>;
+
+    fn microphone_set_state(&self, state: MicrophoneState) -> Result<()>;
+
+    fn microphone_get_state(&self) -> Result<MicrophoneState>;
+
+    fn get_current_user(&self) -> Result<Option<String>>;
+
+    /// Feeds `words` to the loaded grammars as if they had been recognized.
+    /// Returns whether any grammar accepted them.
+    fn mimic(&self, words: &[String]) -> Result<bool>;
+
+    /// Fails if the engine can no longer be reached.
+    fn ping(&self) -> Result<()> {
+        self.microphone_get_state().map(|_| ())
+    }
+
+    /// Gets the calling thread ready to talk to the engine. Every thread
+    /// that calls into the engine, or connects to it, does this first.
+    fn prepare_thread() -> Result<()> {
+        Ok(())
+    }
+}
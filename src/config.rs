@@ -0,0 +1,210 @@
+use crate::errors::*;
+use crate::queue::OverflowPolicy;
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use stentorian::grammar::Grammar;
+
+fn invalid(reason: String) -> MyError {
+    ErrorKind::InvalidConfig { reason }.into()
+}
+
+/// Settings read from the configuration file. Every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub tls: Option<TlsConfig>,
+    pub logging: LoggingConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    pub admin: AdminConfig,
+    pub grammars: Vec<PreloadConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub host: IpAddr,
+    pub port: Option<u16>,
+    pub ws_port: Option<u16>,
+    /// Origins of web pages that may open WebSocket connections. Clients
+    /// that are not browsers do not send an origin and are not affected.
+    pub ws_origins: Vec<String>,
+    pub wait_seconds: Option<u64>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: None,
+            ws_port: None,
+            ws_origins: Vec::new(),
+            wait_seconds: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Filter in `RUST_LOG` syntax, which takes precedence if it is set.
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub session_grace_seconds: u64,
+    /// How long clients holding pauses get before the engine is resumed
+    /// without them.
+    pub pause_timeout_ms: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            queue_size: 1000,
+            overflow_policy: OverflowPolicy::DropOldest,
+            session_grace_seconds: 0,
+            pause_timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub allow_shutdown: bool,
+}
+
+/// A command grammar the server loads at startup, independently of any
+/// client.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreloadConfig {
+    pub file: PathBuf,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+/// Where the configuration is read from when no path is given.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("stentorian").join("server.toml"))
+}
+
+impl Config {
+    /// Reads the configuration from `path`, or from the default location if
+    /// no path is given. Only an explicitly given file has to exist.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match default_path() {
+                Some(ref path) if path.exists() => path.clone(),
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| invalid(format!("could not read {}: {}", path.display(), e)))?;
+        let mut config: Config =
+            toml::from_str(&contents).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+
+        // paths in the file are relative to the file itself
+        if let Some(base) = path.parent() {
+            config.resolve_paths(base);
+        }
+
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |p: &mut PathBuf| *p = base.join(&*p);
+
+        if let Some(ref mut tls) = self.tls {
+            resolve(&mut tls.cert);
+            resolve(&mut tls.key);
+            if let Some(ref mut ca) = tls.client_ca {
+                resolve(ca);
+            }
+        }
+
+        if let Some(ref mut token_file) = self.auth.token_file {
+            resolve(token_file);
+        }
+
+        for grammar in &mut self.grammars {
+            resolve(&mut grammar.file);
+        }
+    }
+
+    /// Checks what can be checked without touching the network or the
+    /// engine. The listeners are only checked if `listening` is set, since
+    /// a replay does not accept any clients.
+    pub fn validate(&self, listening: bool) -> Result<()> {
+        if listening && self.listen.port.is_none() && self.listen.ws_port.is_none() {
+            return Err(invalid(
+                "specify at least one of --port and --ws-port".to_owned(),
+            ));
+        }
+
+        // without a token anyone who can reach the server can use it
+        if listening && self.auth.token_file.is_none() && !self.listen.host.is_loopback() {
+            return Err(invalid(format!(
+                "a token file is required to listen on {}",
+                self.listen.host
+            )));
+        }
+
+        // every local user, and every web page whose origin is let in, can
+        // reach a WebSocket listener even on a loopback address
+        if listening && self.auth.token_file.is_none() && self.listen.ws_port.is_some() {
+            return Err(invalid(
+                "a token file is required to accept WebSocket connections".to_owned(),
+            ));
+        }
+
+        if self.limits.queue_size == 0 {
+            return Err(invalid("queue size should be at least 1".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+impl PreloadConfig {
+    pub fn read_grammar(&self) -> Result<Grammar> {
+        let contents = fs::read_to_string(&self.file)
+            .map_err(|e| invalid(format!("could not read {}: {}", self.file.display(), e)))?;
+
+        serde_json::from_str(&contents)
+            .map_err(|e| invalid(format!("invalid grammar in {}: {}", self.file.display(), e)))
+    }
+}
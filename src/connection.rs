@@ -0,0 +1,263 @@
+use crate::auth::{AuthMiddleware, AuthState, Authenticator, RpcAuthImpl};
+use crate::backend::Backend;
+use crate::connector::{Connector, RpcStatusImpl};
+use crate::discover::RpcDiscoverImpl;
+use crate::errors::*;
+use crate::events::EngineEvents;
+use crate::filter::{Filter, Notifier, RpcFilterImpl};
+use crate::notifications::create_shutdown_notification;
+use crate::protocol::{Capability, Protocol, ProtocolMiddleware, RpcProtocolImpl, Transport};
+use crate::queue::{NotificationSender, QueueConfig};
+use crate::record::Recorder;
+use crate::rpc::*;
+use crate::rpcimpl::*;
+use crate::session::{Attachment, Session, SessionRegistry};
+use crate::shared::SharedGrammars;
+use crate::shutdown::{RpcAdminImpl, Shutdown};
+use futures::stream;
+use futures::{Future, Sink, Stream};
+use jsonrpc_core::MetaIoHandler;
+use log::{error, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_core::reactor::Handle;
+
+/// Handles the requests of a session. Calls are named as in the session's
+/// protocol version, and refused until the client is authenticated.
+pub type Handler = MetaIoHandler<(), (ProtocolMiddleware, AuthMiddleware)>;
+
+/// State shared by all connections of the server.
+pub struct Shared<B: Backend> {
+    pub engine: Arc<Connector<B>>,
+    /// Engine events, passed on to every authenticated connection.
+    pub events: Arc<EngineEvents<Connector<B>>>,
+    /// Command grammars that connections can attach to by name.
+    pub grammars: Arc<SharedGrammars<Connector<B>>>,
+    pub authenticator: Arc<Authenticator>,
+    pub queue: QueueConfig,
+    pub sessions: Arc<SessionRegistry>,
+    pub shutdown: Arc<Shutdown>,
+    /// Whether clients may shut the server down with `server_shutdown`.
+    pub allow_shutdown: bool,
+    /// Where requests are written to when the server is recording.
+    pub recorder: Option<Arc<Recorder>>,
+    /// How clients can connect, as reported by `hello`.
+    pub transports: Vec<Transport>,
+}
+
+impl<B: Backend> Shared<B> {
+    /// What the server supports, as configured.
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+
+        if self.authenticator.is_required() {
+            capabilities.push(Capability::Auth);
+        }
+        capabilities.push(Capability::Mimic);
+        if self.sessions.is_enabled() {
+            capabilities.push(Capability::Sessions);
+        }
+        capabilities.push(Capability::Filters);
+        capabilities.push(Capability::SharedGrammars);
+        capabilities.push(Capability::PauseHolding);
+        if self.allow_shutdown {
+            capabilities.push(Capability::Shutdown);
+        }
+        capabilities.push(Capability::Discover);
+
+        capabilities
+    }
+}
+
+pub fn create_handler<B: Backend>(
+    shared: &Shared<B>,
+    notifications: NotificationSender,
+    protocol: Arc<Protocol>,
+    auth: Arc<AuthState>,
+    ids: Arc<GrammarIds>,
+) -> Handler {
+    let middleware = (
+        ProtocolMiddleware(protocol.clone()),
+        AuthMiddleware(auth.clone()),
+    );
+    let mut handler = MetaIoHandler::with_middleware(middleware);
+    let engine = shared.engine.clone();
+    let filter = Filter::new();
+    let rpc_protocol = RpcProtocolImpl::new(
+        protocol.clone(),
+        shared.transports.clone(),
+        shared.capabilities(),
+    );
+    let notifications = Notifier::new(notifications, filter.clone(), shared.events.clock());
+    let rpc_command = RpcCommandImpl(
+        RpcHelper::new(
+            engine.clone(),
+            notifications.clone(),
+            GrammarKind::Command,
+            ids.clone(),
+        ),
+        shared.grammars.clone(),
+    );
+    let rpc_select = RpcSelectImpl(RpcHelper::new(
+        engine.clone(),
+        notifications.clone(),
+        GrammarKind::Select,
+        ids.clone(),
+    ));
+    let rpc_dictation = RpcDictationImpl(RpcHelper::new(
+        engine.clone(),
+        notifications.clone(),
+        GrammarKind::Dictation,
+        ids.clone(),
+    ));
+    let rpc_catchall = RpcCatchallImpl(RpcHelper::new(
+        engine.clone(),
+        notifications.clone(),
+        GrammarKind::Catchall,
+        ids.clone(),
+    ));
+    let rpc_engine = RpcEngineImpl(
+        RpcHelper::new(
+            engine.clone(),
+            notifications,
+            GrammarKind::Engine,
+            ids.clone(),
+        ),
+        shared.events.clone(),
+    );
+    let rpc_filter = RpcFilterImpl {
+        ids: ids.clone(),
+        filter,
+    };
+    let rpc_introspect = RpcIntrospectImpl(ids);
+    let rpc_status = RpcStatusImpl(engine);
+    let rpc_admin = RpcAdminImpl {
+        shutdown: shared.shutdown.clone(),
+        allowed: shared.allow_shutdown,
+        peer: auth.peer(),
+    };
+
+    handler.extend_with(RpcAuthImpl(auth).to_delegate());
+    handler.extend_with(rpc_admin.to_delegate());
+    handler.extend_with(rpc_command.to_delegate());
+    handler.extend_with(rpc_select.to_delegate());
+    handler.extend_with(rpc_dictation.to_delegate());
+    handler.extend_with(rpc_catchall.to_delegate());
+    handler.extend_with(rpc_engine.to_delegate());
+    handler.extend_with(rpc_filter.to_delegate());
+    handler.extend_with(rpc_protocol.to_delegate());
+    handler.extend_with(rpc_introspect.to_delegate());
+    handler.extend_with(rpc_status.to_delegate());
+    handler.extend_with(RpcDiscoverImpl(protocol).to_delegate());
+
+    handler
+}
+
+/// Serves the JSON-RPC API on a single connection. `requests` yields one
+/// JSON-RPC message at a time, and every response and notification is sent
+/// to `responses`. The returned future completes when the connection closes.
+pub fn handle_connection<B, I, O>(
+    shared: &Shared<B>,
+    handle: &Handle,
+    peer: SocketAddr,
+    requests: I,
+    responses: O,
+) -> impl Future<Item = (), Error = ()>
+where
+    B: Backend,
+    I: Stream<Item = String>,
+    O: Sink<SinkItem = String>,
+    MyError: From<I::Error> + From<O::SinkError>,
+{
+    let recording = shared
+        .recorder
+        .clone()
+        .map(|recorder| (recorder.new_connection(), recorder));
+    let connection = recording.as_ref().map(|&(connection, _)| connection);
+    let attachment = Attachment::new(Session::new(shared, peer, connection));
+    let requests_recording = recording.clone();
+    let notifications_rx = attachment
+        .clone()
+        .map(|x| Some(x))
+        .chain(stream::once(Ok(None)));
+
+    let requests_attachment = attachment.clone();
+    let request_results = requests
+        .and_then(move |r| {
+            let attachment = requests_attachment.clone();
+
+            if let Some((connection, ref recorder)) = requests_recording {
+                recorder.request(connection, &r);
+            }
+
+            requests_attachment
+                .handle_request(&r)
+                .map_err(|()| panic!("handle_request should never fail"))
+                .map(move |response| (response, attachment.is_rejected()))
+        })
+        .from_err()
+        .map(|(response, rejected)| {
+            let mut items: Vec<_> = response.into_iter().map(Some).collect();
+
+            // a rejected client gets to see the error, and is then cut off
+            if rejected {
+                items.push(None);
+            }
+
+            stream::iter_ok::<_, MyError>(items)
+        })
+        .flatten()
+        .chain(stream::once(Ok(None)));
+
+    // once the server shuts down, the client is told why and cut off
+    let shutdown_attachment = attachment.clone();
+    let shutdown_rx = shared
+        .shutdown
+        .triggered()
+        .and_then(move |reason| {
+            let notification = create_shutdown_notification(&reason.describe());
+            shutdown_attachment.session().render(&notification)
+        })
+        .map(|n| stream::iter_ok::<_, MyError>(vec![Some(n), None]))
+        .flatten_stream();
+
+    let merged = request_results
+        .select(notifications_rx)
+        .select(shutdown_rx)
+        .take_while(|x| Ok(x.is_some()))
+        .filter_map(|x| x);
+
+    let sessions = shared.sessions.clone();
+    let shutdown = shared.shutdown.clone();
+    let guard = shared.shutdown.connection();
+    let handle = handle.clone();
+
+    merged.forward(responses).then(move |r: Result<_>| {
+        match r {
+            Ok(_) => {}
+            Err(e) => {
+                error!("{}", e.0);
+            }
+        }
+
+        info!("connection with {} closed", peer);
+
+        if let Some((connection, recorder)) = recording {
+            recorder.disconnect(connection);
+        }
+
+        {
+            // nobody is coming back for the session of a server going down
+            let session = attachment.session();
+            drop(attachment);
+
+            if !session.is_rejected() && !shutdown.is_triggered() {
+                sessions.detach(session, &handle);
+            }
+        }
+
+        // only counts as closed once its grammars are unloaded
+        drop(guard);
+        Ok(())
+    })
+}
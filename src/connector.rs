@@ -0,0 +1,385 @@
+use crate::backend::*;
+use crate::errors::*;
+use crate::notifications::{create_recovered_notification, create_status_notification, Outgoing};
+use crate::queue::NotificationSender;
+use crate::recovery::*;
+use crate::rpc::RpcStatus;
+use crate::validate;
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+use stentorian::engine::{
+    CatchallGrammarEvent, CommandGrammarEvent, DictationGrammarEvent, MicrophoneState,
+    SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EngineStatus {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+/// The engine, along with a counter that goes up every time the connection
+/// is made again.
+type Current<B> = Option<(u64, Arc<B>)>;
+
+/// A backend that may not be connected at the moment. While it is not,
+/// every call fails with `EngineNotConnected`, so the server can accept
+/// clients while the engine is still starting up or restarting.
+///
+/// Everything loaded through the connector is recorded, and loaded again
+/// once the engine comes back after a restart.
+pub struct Connector<B: Backend> {
+    engine: RwLock<Current<B>>,
+    generation: Mutex<u64>,
+    slots: Mutex<Vec<Weak<dyn Recover<B>>>>,
+    watchers: Mutex<Vec<NotificationSender>>,
+    on_connected: Mutex<Vec<Hook>>,
+}
+
+impl<B: Backend> Connector<B> {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Connector {
+            engine: RwLock::new(None),
+            generation: Mutex::new(0),
+            slots: Mutex::new(Vec::new()),
+            watchers: Mutex::new(Vec::new()),
+            on_connected: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Connects on a background thread, starting after `delay`, and keeps
+    /// the connection up from then on. `connect` is retried with increasing
+    /// waits until it succeeds, and called again if the engine goes away.
+    pub fn start<F>(self: &Arc<Self>, delay: Duration, mut connect: F)
+    where
+        F: FnMut() -> Result<B> + Send + 'static,
+    {
+        let connector = self.clone();
+
+        thread::spawn(move || {
+            thread::sleep(delay);
+
+            loop {
+                let engine = Arc::new(connect_with_backoff(&mut || {
+                    B::prepare_thread()?;
+                    connect()
+                }));
+                connector.connected(engine.clone());
+
+                while engine.ping().is_ok() {
+                    thread::sleep(HEALTH_CHECK_INTERVAL);
+                }
+
+                connector.lost();
+            }
+        });
+    }
+
+    /// Runs `f` once the engine is connected, which may be right away. It
+    /// only runs once: what it loads through the connector is loaded again
+    /// after a restart anyway.
+    pub fn when_connected<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let mut hooks = lock(&self.on_connected);
+
+        if self.engine().is_ok() {
+            drop(hooks);
+            f();
+        } else {
+            hooks.push(Box::new(f));
+        }
+    }
+
+    fn run_hooks(&self) {
+        let hooks: Vec<Hook> = lock(&self.on_connected).drain(..).collect();
+        for f in hooks {
+            f();
+        }
+    }
+
+    /// Uses `engine` right away, for engines that cannot go away.
+    pub fn attach(&self, engine: B) {
+        self.connected(Arc::new(engine));
+    }
+
+    fn connected(&self, engine: Arc<B>) {
+        let generation = {
+            let mut generation = lock(&self.generation);
+            *generation += 1;
+            *generation
+        };
+
+        *self.engine.write().expect("attempt to lock poisoned lock") =
+            Some((generation, engine.clone()));
+        info!("connected to the engine");
+        self.broadcast(create_status_notification(EngineStatus::Connected));
+
+        if generation == 1 {
+            self.run_hooks();
+            return;
+        }
+
+        let slots: Vec<Arc<dyn Recover<B>>> = {
+            let mut slots = lock(&self.slots);
+            slots.retain(|s| s.upgrade().is_some());
+            slots.iter().filter_map(|s| s.upgrade()).collect()
+        };
+
+        let mut failed = 0;
+        for slot in &slots {
+            if let Err(e) = slot.reload(&engine, generation) {
+                error!("could not reload grammar after engine restart: {}", e.0);
+                failed += 1;
+            }
+        }
+
+        let reloaded = slots.len() - failed;
+        info!("reloaded {} grammars, {} failed", reloaded, failed);
+        self.broadcast(Ok(create_recovered_notification(generation)));
+        self.run_hooks();
+    }
+
+    fn lost(&self) {
+        warn!("lost the connection to the engine");
+        *self.engine.write().expect("attempt to lock poisoned lock") = None;
+
+        // the controls belong to the old engine, so they are of no use
+        // anymore
+        let slots: Vec<Arc<dyn Recover<B>>> = lock(&self.slots)
+            .iter()
+            .filter_map(|s| s.upgrade())
+            .collect();
+        for slot in slots {
+            slot.detach();
+        }
+
+        self.broadcast(create_status_notification(EngineStatus::Disconnected));
+    }
+
+    fn broadcast(&self, notification: Result<Outgoing>) {
+        let mut watchers = lock(&self.watchers);
+        watchers.retain(|w| !w.is_closed());
+
+        let notification = match notification {
+            Ok(notification) => notification,
+            Err(e) => {
+                error!("{}", e.0);
+                return;
+            }
+        };
+
+        for watcher in watchers.iter() {
+            watcher.send(Ok(notification.clone()));
+        }
+    }
+
+    pub fn status(&self) -> EngineStatus {
+        let generation = *lock(&self.generation);
+
+        match *self.engine.read().expect("attempt to lock poisoned lock") {
+            Some(_) => EngineStatus::Connected,
+            None if generation == 0 => EngineStatus::Connecting,
+            None => EngineStatus::Disconnected,
+        }
+    }
+
+    /// Sends `engine_status_changed` and `engine_recovered` notifications to
+    /// `watcher` whenever the connection to the engine changes.
+    pub fn watch(&self, watcher: NotificationSender) {
+        let mut watchers = lock(&self.watchers);
+        watchers.retain(|w| !w.is_closed());
+        watchers.push(watcher);
+    }
+
+    fn engine(&self) -> Result<(u64, Arc<B>)> {
+        match *self.engine.read().expect("attempt to lock poisoned lock") {
+            Some(ref current) => Ok(current.clone()),
+            None => Err(ErrorKind::EngineNotConnected.into()),
+        }
+    }
+
+    /// Loads `state` and keeps track of it for when the engine restarts.
+    fn track<S: Restore<B>>(&self, state: S) -> Result<Recoverable<B, S>> {
+        let (generation, engine) = self.engine()?;
+        let recoverable = Recoverable::load(&*engine, generation, state)?;
+
+        let cell = recoverable.cell();
+        let weak: Weak<dyn Recover<B>> = Arc::downgrade(&cell);
+        {
+            let mut slots = lock(&self.slots);
+            slots.retain(|s| s.upgrade().is_some());
+            slots.push(weak);
+        }
+
+        // the engine may have restarted while this was being loaded, in
+        // which case the recovery did not know about it yet
+        if let Ok((current, engine)) = self.engine() {
+            if current != generation {
+                cell.reload(&*engine, current)?;
+            }
+        }
+
+        Ok(recoverable)
+    }
+}
+
+fn connect_with_backoff<B, F>(connect: &mut F) -> B
+where
+    F: FnMut() -> Result<B>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        match connect() {
+            Ok(engine) => return engine,
+            Err(e) => {
+                warn!(
+                    "could not connect to the engine, retrying in {} seconds: {}",
+                    backoff.as_secs(),
+                    e.0
+                );
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+impl<B: Backend> Backend for Connector<B> {
+    type CommandControl = Recoverable<B, CommandState>;
+    type SelectControl = Recoverable<B, SelectState>;
+    type DictationControl = Recoverable<B, DictationState>;
+    type CatchallControl = Recoverable<B, CatchallState>;
+    type Registration = Recoverable<B, RegistrationState<B>>;
+    type PauseCookie = B::PauseCookie;
+
+    fn command_grammar_load<F>(
+        &self,
+        grammar: &Grammar,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CommandControl>
+    where
+        F: Fn(CommandGrammarEvent) + Sync + Send + 'static,
+    {
+        validate::grammar(grammar)?;
+
+        self.track(CommandState {
+            grammar: grammar.clone(),
+            label,
+            callback: Arc::new(callback),
+            active_rules: BTreeSet::new(),
+            lists: BTreeMap::new(),
+        })
+    }
+
+    fn select_grammar_load<F>(
+        &self,
+        select_words: &[String],
+        through_words: &[String],
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::SelectControl>
+    where
+        F: Fn(SelectGrammarEvent) + Sync + Send + 'static,
+    {
+        self.track(SelectState {
+            select_words: select_words.to_vec(),
+            through_words: through_words.to_vec(),
+            label,
+            callback: Arc::new(callback),
+            active: false,
+            text: String::new(),
+        })
+    }
+
+    fn dictation_grammar_load<F>(
+        &self,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::DictationControl>
+    where
+        F: Fn(DictationGrammarEvent) + Sync + Send + 'static,
+    {
+        self.track(DictationState {
+            label,
+            callback: Arc::new(callback),
+            active: false,
+            context: None,
+        })
+    }
+
+    fn catchall_grammar_load<F>(
+        &self,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CatchallControl>
+    where
+        F: Fn(CatchallGrammarEvent) + Sync + Send + 'static,
+    {
+        self.track(CatchallState {
+            label,
+            callback: Arc::new(callback),
+            active: false,
+        })
+    }
+
+    fn register<F>(&self, callback: F) -> Result<Self::Registration>
+    where
+        F: Fn(EngineEvent<B::PauseCookie>) + Sync + Send + 'static,
+    {
+        self.track(RegistrationState {
+            callback: Arc::new(callback),
+        })
+    }
+
+    fn resume(&self, cookie: B::PauseCookie) -> Result<()> {
+        self.engine()?.1.resume(cookie)
+    }
+
+    fn microphone_set_state(&self, state: MicrophoneState) -> Result<()> {
+        self.engine()?.1.microphone_set_state(state)
+    }
+
+    fn microphone_get_state(&self) -> Result<MicrophoneState> {
+        self.engine()?.1.microphone_get_state()
+    }
+
+    fn get_current_user(&self) -> Result<Option<String>> {
+        self.engine()?.1.get_current_user()
+    }
+
+    fn mimic(&self, words: &[String]) -> Result<bool> {
+        self.engine()?.1.mimic(words)
+    }
+
+    fn prepare_thread() -> Result<()> {
+        B::prepare_thread()
+    }
+}
+
+pub struct RpcStatusImpl<B: Backend>(pub Arc<Connector<B>>);
+
+impl<B: Backend> RpcStatus for RpcStatusImpl<B> {
+    fn engine_status(&self) -> Result<EngineStatus> {
+        Ok(self.0.status())
+    }
+}
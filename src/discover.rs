@@ -0,0 +1,547 @@
+use crate::connector::EngineStatus;
+use crate::errors::*;
+use crate::filter::EventKind;
+use crate::notifications::{EngineNotification, Meta, RecoveredSummary, ShutdownDetails};
+use crate::protocol::{MethodChanges, Protocol, ServerInfo};
+use crate::rpc::{describe_methods, RpcDiscover};
+use crate::rpcimpl::GrammarInfo;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use stentorian::engine::{GrammarEvent, MicrophoneState, Recognition};
+use stentorian::grammar::Grammar;
+
+/// Types that can describe their JSON representation as a JSON Schema.
+/// The schemas come from `JsonSchema`, which is derived from the same
+/// serde attributes that decide what goes over the wire.
+pub trait Describe {
+    /// Whether the type can be left out as the last param of a call.
+    const OPTIONAL: bool = false;
+
+    fn describe(gen: &mut SchemaGenerator) -> Value;
+}
+
+fn schema_for<T: JsonSchema>(gen: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(gen.subschema_for::<T>()).expect("schema should serialize")
+}
+
+macro_rules! describe_with_schema {
+    ($($t:ty),*) => {
+        $(
+            impl Describe for $t {
+                fn describe(gen: &mut SchemaGenerator) -> Value {
+                    schema_for::<$t>(gen)
+                }
+            }
+        )*
+    };
+}
+
+describe_with_schema!(
+    (),
+    bool,
+    u32,
+    u64,
+    String,
+    Value,
+    EngineStatus,
+    EventKind,
+    GrammarInfo,
+    ServerInfo,
+    EngineNotification,
+    RecoveredSummary,
+    ShutdownDetails,
+    Meta
+);
+
+impl<T: Describe> Describe for Option<T> {
+    const OPTIONAL: bool = true;
+
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        json!({ "anyOf": [T::describe(gen), { "type": "null" }] })
+    }
+}
+
+impl<T: Describe> Describe for Vec<T> {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        json!({ "type": "array", "items": T::describe(gen) })
+    }
+}
+
+impl<T: Describe> Describe for BTreeSet<T> {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        json!({ "type": "array", "items": T::describe(gen), "uniqueItems": true })
+    }
+}
+
+impl<T: Describe> Describe for BTreeMap<String, T> {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        json!({ "type": "object", "additionalProperties": T::describe(gen) })
+    }
+}
+
+impl<A: Describe, B: Describe> Describe for (A, B) {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        json!({
+            "type": "array",
+            "items": [A::describe(gen), B::describe(gen)],
+            "minItems": 2,
+            "maxItems": 2,
+        })
+    }
+}
+
+/// Stand-ins for the types from stentorian that are part of the API, which
+/// cannot derive a schema themselves. `mirrors_match_stentorian` checks
+/// that what stentorian sends fits them.
+#[allow(dead_code)]
+pub mod mirrors {
+    use schemars::JsonSchema;
+    use serde_json::Value;
+
+    #[derive(JsonSchema)]
+    #[serde(rename = "MicrophoneState", rename_all = "snake_case")]
+    pub enum MicrophoneStateDef {
+        Disabled,
+        Off,
+        On,
+        Sleeping,
+    }
+
+    #[derive(JsonSchema)]
+    #[serde(rename = "Grammar")]
+    pub struct GrammarDef {
+        rules: Vec<RuleDef>,
+    }
+
+    #[derive(JsonSchema)]
+    #[serde(rename = "Rule")]
+    struct RuleDef {
+        name: String,
+        exported: bool,
+        definition: ElementDef,
+    }
+
+    #[derive(JsonSchema)]
+    #[serde(rename = "Element", tag = "type", rename_all = "snake_case")]
+    enum ElementDef {
+        Sequence { children: Vec<ElementDef> },
+        Alternative { children: Vec<ElementDef> },
+        Repetition { child: Box<ElementDef> },
+        Optional { child: Box<ElementDef> },
+        Capture { key: String, child: Box<ElementDef> },
+        Word { text: String },
+        RuleRef { name: String },
+        List { name: String },
+        Dictation,
+        DictationWord,
+        SpellingLetter,
+    }
+
+    /// A failed call, the way `grammar_reload_failed` reports it.
+    #[derive(JsonSchema)]
+    #[serde(rename = "Error")]
+    pub struct RpcErrorDef {
+        code: i64,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<Value>,
+    }
+}
+
+impl Describe for MicrophoneState {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        schema_for::<mirrors::MicrophoneStateDef>(gen)
+    }
+}
+
+impl Describe for Grammar {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        schema_for::<mirrors::GrammarDef>(gen)
+    }
+}
+
+impl Describe for MyError {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        schema_for::<mirrors::RpcErrorDef>(gen)
+    }
+}
+
+/// One event for every outcome of an utterance. There is no catch-all
+/// arm, so a new kind of event does not compile until it is described.
+fn event_samples<T>(payload: T) -> Vec<GrammarEvent<T>> {
+    let samples = vec![
+        GrammarEvent::PhraseFinish(Recognition::Self_(payload)),
+        GrammarEvent::PhraseFinish(Recognition::Other),
+        GrammarEvent::PhraseFinish(Recognition::Reject),
+    ];
+
+    for sample in &samples {
+        match sample {
+            GrammarEvent::PhraseFinish(Recognition::Self_(_))
+            | GrammarEvent::PhraseFinish(Recognition::Other)
+            | GrammarEvent::PhraseFinish(Recognition::Reject) => {}
+        }
+    }
+
+    samples
+}
+
+/// Derives a schema from a serialized sample. Strings in the sample are
+/// taken to be tags, except for `marker`, which stands for `payload`.
+fn infer(sample: &Value, marker: &str, payload: &Value) -> Value {
+    match sample {
+        Value::String(s) if s == marker => payload.clone(),
+        Value::String(s) => json!({ "const": s }),
+        Value::Null => json!({ "type": "null" }),
+        Value::Bool(_) => json!({ "type": "boolean" }),
+        Value::Number(_) => json!({ "type": "number" }),
+        Value::Array(items) => {
+            let items: Vec<Value> = items.iter().map(|i| infer(i, marker, payload)).collect();
+            json!({ "type": "array", "items": items })
+        }
+        Value::Object(fields) => {
+            let properties: Map<String, Value> = fields
+                .iter()
+                .map(|(k, v)| (k.clone(), infer(v, marker, payload)))
+                .collect();
+            let required: Vec<&String> = fields.keys().collect();
+            json!({ "type": "object", "properties": properties, "required": required })
+        }
+    }
+}
+
+const PAYLOAD_MARKER: &str = "\u{0}payload";
+
+/// Described from stentorian's own serialization of every outcome, so the
+/// tags are exactly what it sends.
+impl<T: Describe> Describe for GrammarEvent<T> {
+    fn describe(gen: &mut SchemaGenerator) -> Value {
+        let payload = T::describe(gen);
+        let variants: Vec<Value> = event_samples(PAYLOAD_MARKER)
+            .iter()
+            .map(|s| {
+                let sample = serde_json::to_value(s).expect("grammar event should serialize");
+                infer(&sample, PAYLOAD_MARKER, &payload)
+            })
+            .collect();
+
+        json!({ "oneOf": variants })
+    }
+}
+
+pub struct Param {
+    name: &'static str,
+    required: bool,
+    describe: fn(&mut SchemaGenerator) -> Value,
+}
+
+impl Param {
+    pub fn of<T: Describe>(name: &'static str) -> Self {
+        Param {
+            name,
+            required: !T::OPTIONAL,
+            describe: T::describe,
+        }
+    }
+}
+
+pub struct Document {
+    gen: SchemaGenerator,
+    changes: &'static MethodChanges,
+    methods: Vec<Value>,
+    notifications: Vec<Value>,
+}
+
+impl Document {
+    fn params(&mut self, params: Vec<Param>) -> Vec<Value> {
+        params
+            .into_iter()
+            .map(|p| {
+                json!({
+                    "name": p.name,
+                    "required": p.required,
+                    "schema": (p.describe)(&mut self.gen),
+                })
+            })
+            .collect()
+    }
+
+    /// Describes the method of rpc.rs called `name`, under the name the
+    /// protocol version has for it, if it has it at all.
+    pub fn method<R: Describe>(&mut self, name: &str, params: Vec<Param>) {
+        let name = match self.changes.name_of(name) {
+            Some(name) => name,
+            None => return,
+        };
+
+        let params = self.params(params);
+        let result = R::describe(&mut self.gen);
+
+        self.methods.push(json!({
+            "name": name,
+            "paramStructure": "by-position",
+            "params": params,
+            "result": { "name": "result", "schema": result },
+        }));
+    }
+
+    /// From protocol version 2 on, every notification ends with the same
+    /// `meta` parameter: the sequence number of the notification on its
+    /// connection, when it was sent and, for the end of an utterance, when
+    /// the engine paused before it. Times are the server's wall-clock time,
+    /// in milliseconds since the Unix epoch.
+    fn server_notification(&mut self, name: &str, params: Vec<Param>) {
+        let mut params = self.params(params);
+        let meta = Meta::describe(&mut self.gen);
+        params.push(json!({ "name": "meta", "required": false, "schema": meta }));
+
+        // positional in protocol version 1, which leaves out `meta`, and
+        // named after that, in which case the fields of `meta` are params
+        // of their own
+        self.notifications.push(json!({
+            "name": name,
+            "paramStructure": "either",
+            "params": params,
+        }));
+    }
+
+    /// Notifications are sent with the id of the grammar or registration
+    /// they belong to, followed by the event.
+    fn notification<E: Describe>(&mut self, name: &str) {
+        let params = vec![Param::of::<u64>("grammar_id"), Param::of::<E>("event")];
+        self.server_notification(name, params);
+    }
+}
+
+/// Builds the OpenRPC description of the API. The methods are the ones
+/// declared in rpc.rs, changed as `changes` says for the protocol version
+/// being described. Notifications are not part of OpenRPC, so they are
+/// listed under `x-notifications` in the same format as methods without a
+/// result.
+pub fn document(changes: &'static MethodChanges) -> Value {
+    let settings = SchemaSettings::draft07().with(|s| {
+        s.definitions_path = "#/components/schemas/".to_owned();
+    });
+    let mut doc = Document {
+        gen: settings.into_generator(),
+        changes,
+        methods: Vec::new(),
+        notifications: Vec::new(),
+    };
+
+    describe_methods(&mut doc);
+
+    // the words, along with the captures of the grammar if they matched it
+    doc.notification::<GrammarEvent<(Vec<String>, Option<Value>)>>("command_grammar_notification");
+    doc.notification::<GrammarEvent<Vec<String>>>("select_grammar_notification");
+    doc.notification::<GrammarEvent<Vec<String>>>("dictation_grammar_notification");
+    doc.notification::<GrammarEvent<Vec<String>>>("catchall_grammar_notification");
+    doc.notification::<EngineNotification>("engine_notification");
+
+    doc.server_notification("notifications_dropped", vec![Param::of::<u64>("count")]);
+    doc.server_notification(
+        "engine_status_changed",
+        vec![Param::of::<EngineStatus>("status")],
+    );
+
+    // sent to authenticated clients that did not call engine_register
+    doc.server_notification(
+        "engine_event",
+        vec![Param::of::<EngineNotification>("event")],
+    );
+
+    // sent after an engine restart, once every grammar was loaded again
+    doc.server_notification(
+        "engine_recovered",
+        vec![Param::of::<RecoveredSummary>("summary")],
+    );
+
+    // sent to the owner of a grammar that could not be loaded again, with
+    // the error as it would be reported for a request
+    doc.server_notification(
+        "grammar_reload_failed",
+        vec![
+            Param::of::<u64>("grammar_id"),
+            Param::of::<MyError>("event"),
+        ],
+    );
+
+    doc.server_notification(
+        "server_shutting_down",
+        vec![Param::of::<ShutdownDetails>("details")],
+    );
+
+    let schemas = serde_json::to_value(doc.gen.definitions()).expect("schema should serialize");
+
+    json!({
+        "openrpc": "1.2.6",
+        "info": {
+            "title": "stentorian-server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "methods": doc.methods,
+        "x-notifications": doc.notifications,
+        "components": { "schemas": schemas },
+    })
+}
+
+/// Describes the API as the session's protocol version has it.
+pub struct RpcDiscoverImpl(pub Arc<Protocol>);
+
+impl RpcDiscover for RpcDiscoverImpl {
+    fn discover(&self) -> Result<Value> {
+        Ok(document(self.0.methods()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stentorian::grammar::Element;
+
+    /// Whether `value` fits `schema`, as far as the parts of JSON Schema
+    /// that the document uses go. Objects may not have fields the schema
+    /// does not know about.
+    fn conforms(value: &Value, schema: &Value, defs: &Value) -> bool {
+        if *schema == Value::Bool(true) {
+            return true;
+        }
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            return conforms(value, &defs[name], defs);
+        }
+        if let Some(variants) = schema["oneOf"].as_array().or(schema["anyOf"].as_array()) {
+            return variants.iter().any(|v| conforms(value, v, defs));
+        }
+        if let Some(values) = schema["enum"].as_array() {
+            return values.contains(value);
+        }
+        if let Some(constant) = schema.get("const") {
+            return constant == value;
+        }
+
+        let types: Vec<&str> = match schema["type"] {
+            Value::String(ref t) => vec![t.as_str()],
+            Value::Array(ref ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        let fits = |t: &&str| match *t {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "string" => value.is_string(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            _ => false,
+        };
+        if !types.is_empty() && !types.iter().any(fits) {
+            return false;
+        }
+
+        match value {
+            Value::Object(fields) => {
+                let properties = &schema["properties"];
+                let required = schema["required"].as_array().cloned().unwrap_or_default();
+                required
+                    .iter()
+                    .all(|r| r.as_str().map_or(false, |r| fields.contains_key(r)))
+                    && fields.iter().all(|(k, v)| match properties.get(k) {
+                        Some(property) => conforms(v, property, defs),
+                        None => schema.get("additionalProperties").is_some(),
+                    })
+            }
+            Value::Array(items) => match schema["items"] {
+                Value::Array(ref tuple) => {
+                    items.len() == tuple.len()
+                        && items.iter().zip(tuple).all(|(i, s)| conforms(i, s, defs))
+                }
+                ref item => items.iter().all(|i| conforms(i, item, defs)),
+            },
+            _ => true,
+        }
+    }
+
+    fn reference(name: &str) -> Value {
+        json!({ "$ref": format!("#/components/schemas/{}", name) })
+    }
+
+    /// Every element, so a new kind does not compile until it is mirrored.
+    fn check_element(element: &Element) {
+        match element {
+            Element::Sequence { .. }
+            | Element::Alternative { .. }
+            | Element::Repetition { .. }
+            | Element::Optional { .. }
+            | Element::Capture { .. }
+            | Element::Word { .. }
+            | Element::RuleRef { .. }
+            | Element::List { .. }
+            | Element::Dictation
+            | Element::DictationWord
+            | Element::SpellingLetter => {}
+        }
+    }
+
+    #[test]
+    fn mirrors_match_stentorian() {
+        let document = document(MethodChanges::of(1));
+        let defs = &document["components"]["schemas"];
+
+        let states = [
+            MicrophoneState::Disabled,
+            MicrophoneState::Off,
+            MicrophoneState::On,
+            MicrophoneState::Sleeping,
+        ];
+        for state in &states {
+            match state {
+                MicrophoneState::Disabled
+                | MicrophoneState::Off
+                | MicrophoneState::On
+                | MicrophoneState::Sleeping => {}
+            }
+            let state = serde_json::to_value(state).unwrap();
+            assert!(
+                conforms(&state, &reference("MicrophoneState"), defs),
+                "{}",
+                state
+            );
+        }
+
+        let word = json!({ "type": "word", "text": "hello" });
+        let elements = vec![
+            json!({ "type": "sequence", "children": [word] }),
+            json!({ "type": "alternative", "children": [word] }),
+            json!({ "type": "repetition", "child": word }),
+            json!({ "type": "optional", "child": word }),
+            json!({ "type": "capture", "key": "greeting", "child": word }),
+            json!({ "type": "rule_ref", "name": "other" }),
+            json!({ "type": "list", "name": "people" }),
+            json!({ "type": "dictation" }),
+            json!({ "type": "dictation_word" }),
+            json!({ "type": "spelling_letter" }),
+        ];
+        let rules: Vec<Value> = elements
+            .into_iter()
+            .map(|definition| json!({ "name": "rule", "exported": true, "definition": definition }))
+            .collect();
+        let grammar: Grammar = serde_json::from_value(json!({ "rules": rules })).unwrap();
+        for rule in &grammar.rules {
+            check_element(&rule.definition);
+        }
+        let grammar = serde_json::to_value(&grammar).unwrap();
+        assert!(
+            conforms(&grammar, &reference("Grammar"), defs),
+            "{}",
+            grammar
+        );
+
+        let error = MyError::from(ErrorKind::UnknownGrammar { id: 1 }).to_rpc_error();
+        let error = serde_json::to_value(&error).unwrap();
+        assert!(conforms(&error, &reference("Error"), defs), "{}", error);
+    }
+}
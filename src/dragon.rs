@@ -0,0 +1,235 @@
+use crate::backend::*;
+use crate::errors::{ErrorKind, MyError, Result};
+use crate::mimic::{MimicRegistry, Mimicked};
+use std::io;
+use std::sync::Arc;
+use stentorian::engine::{
+    CatchallGrammarControl, CatchallGrammarEvent, CommandGrammarControl, CommandGrammarEvent,
+    DictationGrammarControl, DictationGrammarEvent, Engine, EngineEvent as DragonEvent,
+    EngineRegistration, MicrophoneState, PauseCookie, SelectGrammarControl, SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+
+/// Backend talking to a running instance of Dragon through COM. The engine
+/// is used from several threads: the one running the server, the one
+/// keeping the connection up and the one expiring pauses. Each of them
+/// initializes COM through `prepare_thread` before touching the engine.
+pub struct DragonEngine {
+    engine: Engine,
+    registry: Arc<MimicRegistry>,
+}
+
+/// Reports an error coming from stentorian as an engine failure, keeping the
+/// HRESULT if the underlying error carries one.
+fn engine_failure<E: Into<failure::Error>>(e: E) -> MyError {
+    let e = e.into();
+    let hresult = e
+        .iter_chain()
+        .filter_map(|c| c.downcast_ref::<io::Error>())
+        .filter_map(|c| c.raw_os_error())
+        .next();
+
+    MyError(e.context(ErrorKind::EngineFailure { hresult }).into())
+}
+
+fn engine_event(e: DragonEvent) -> EngineEvent<PauseCookie> {
+    match e {
+        DragonEvent::Paused(cookie) => EngineEvent::Paused(cookie),
+        DragonEvent::MicrophoneState => EngineEvent::MicrophoneState,
+        DragonEvent::UserChanged => EngineEvent::UserChanged,
+    }
+}
+
+impl DragonEngine {
+    pub fn connect() -> Result<Self> {
+        Ok(DragonEngine {
+            engine: Engine::connect().map_err(engine_failure)?,
+            registry: MimicRegistry::new(),
+        })
+    }
+}
+
+impl Backend for DragonEngine {
+    type CommandControl = Mimicked<CommandGrammarControl>;
+    type SelectControl = Mimicked<SelectGrammarControl>;
+    type DictationControl = Mimicked<DictationGrammarControl>;
+    type CatchallControl = Mimicked<CatchallGrammarControl>;
+    type Registration = EngineRegistration;
+    type PauseCookie = PauseCookie;
+
+    fn command_grammar_load<F>(
+        &self,
+        grammar: &Grammar,
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CommandControl>
+    where
+        F: Fn(CommandGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry
+            .command_grammar_load(grammar, callback, |callback| {
+                self.engine
+                    .command_grammar_load(grammar, callback)
+                    .map_err(engine_failure)
+            })
+    }
+
+    fn select_grammar_load<F>(
+        &self,
+        select_words: &[String],
+        through_words: &[String],
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::SelectControl>
+    where
+        F: Fn(SelectGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry
+            .select_grammar_load(select_words, through_words, callback, |callback| {
+                self.engine
+                    .select_grammar_load(select_words, through_words, callback)
+                    .map_err(engine_failure)
+            })
+    }
+
+    fn dictation_grammar_load<F>(
+        &self,
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::DictationControl>
+    where
+        F: Fn(DictationGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry.dictation_grammar_load(callback, |callback| {
+            self.engine
+                .dictation_grammar_load(callback)
+                .map_err(engine_failure)
+        })
+    }
+
+    fn catchall_grammar_load<F>(
+        &self,
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CatchallControl>
+    where
+        F: Fn(CatchallGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry.catchall_grammar_load(callback, |callback| {
+            self.engine
+                .catchall_grammar_load(callback)
+                .map_err(engine_failure)
+        })
+    }
+
+    fn register<F>(&self, callback: F) -> Result<Self::Registration>
+    where
+        F: Fn(EngineEvent<PauseCookie>) + Sync + Send + 'static,
+    {
+        self.engine
+            .register(move |e| callback(engine_event(e)))
+            .map_err(engine_failure)
+    }
+
+    fn resume(&self, cookie: PauseCookie) -> Result<()> {
+        self.engine.resume(cookie).map_err(engine_failure)
+    }
+
+    fn microphone_set_state(&self, state: MicrophoneState) -> Result<()> {
+        self.engine
+            .microphone_set_state(state)
+            .map_err(engine_failure)
+    }
+
+    fn microphone_get_state(&self) -> Result<MicrophoneState> {
+        self.engine.microphone_get_state().map_err(engine_failure)
+    }
+
+    fn get_current_user(&self) -> Result<Option<String>> {
+        self.engine.get_current_user().map_err(engine_failure)
+    }
+
+    fn mimic(&self, words: &[String]) -> Result<bool> {
+        Ok(self.registry.mimic(words))
+    }
+
+    fn prepare_thread() -> Result<()> {
+        stentorian::initialize().map_err(engine_failure)
+    }
+}
+
+impl CommandControl for CommandGrammarControl {
+    fn rule_activate(&self, name: &str) -> Result<()> {
+        CommandGrammarControl::rule_activate(self, name).map_err(engine_failure)
+    }
+
+    fn rule_deactivate(&self, name: &str) -> Result<()> {
+        CommandGrammarControl::rule_deactivate(self, name).map_err(engine_failure)
+    }
+
+    fn list_append(&self, name: &str, word: &str) -> Result<()> {
+        CommandGrammarControl::list_append(self, name, word).map_err(engine_failure)
+    }
+
+    fn list_remove(&self, name: &str, word: &str) -> Result<()> {
+        CommandGrammarControl::list_remove(self, name, word).map_err(engine_failure)
+    }
+
+    fn list_clear(&self, name: &str) -> Result<()> {
+        CommandGrammarControl::list_clear(self, name).map_err(engine_failure)
+    }
+}
+
+impl SelectControl for SelectGrammarControl {
+    fn activate(&self) -> Result<()> {
+        SelectGrammarControl::activate(self).map_err(engine_failure)
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        SelectGrammarControl::deactivate(self).map_err(engine_failure)
+    }
+
+    fn text_set(&self, text: &str) -> Result<()> {
+        SelectGrammarControl::text_set(self, text).map_err(engine_failure)
+    }
+
+    fn text_change(&self, start: u32, stop: u32, text: &str) -> Result<()> {
+        SelectGrammarControl::text_change(self, start, stop, text).map_err(engine_failure)
+    }
+
+    fn text_delete(&self, start: u32, stop: u32) -> Result<()> {
+        SelectGrammarControl::text_delete(self, start, stop).map_err(engine_failure)
+    }
+
+    fn text_insert(&self, start: u32, text: &str) -> Result<()> {
+        SelectGrammarControl::text_insert(self, start, text).map_err(engine_failure)
+    }
+
+    fn text_get(&self) -> Result<String> {
+        SelectGrammarControl::text_get(self).map_err(engine_failure)
+    }
+}
+
+impl DictationControl for DictationGrammarControl {
+    fn activate(&self) -> Result<()> {
+        DictationGrammarControl::activate(self).map_err(engine_failure)
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        DictationGrammarControl::deactivate(self).map_err(engine_failure)
+    }
+
+    fn context_set(&self, context: &str) -> Result<()> {
+        DictationGrammarControl::context_set(self, context).map_err(engine_failure)
+    }
+}
+
+impl CatchallControl for CatchallGrammarControl {
+    fn activate(&self) -> Result<()> {
+        CatchallGrammarControl::activate(self).map_err(engine_failure)
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        CatchallGrammarControl::deactivate(self).map_err(engine_failure)
+    }
+}
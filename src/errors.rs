@@ -1,6 +1,9 @@
-use failure::Error;
+use crate::rpcimpl::GrammarKind;
+use failure::{Context, Error, Fail};
 use jsonrpc_core::Error as RpcError;
 use jsonrpc_core::ErrorCode;
+use serde::Serialize;
+use serde_json::{json, Value};
 
 pub type Result<T> = ::std::result::Result<T, MyError>;
 
@@ -12,12 +15,129 @@ impl<T: Into<Error>> From<T> for MyError {
     }
 }
 
-impl From<MyError> for RpcError {
-    fn from(e: MyError) -> RpcError {
+/// Failures that clients are expected to handle. Each of them is reported
+/// with its own error code, see `ErrorKind::code`.
+#[derive(Debug, Fail, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ErrorKind {
+    #[fail(display = "no grammar with id {}", id)]
+    UnknownGrammar { id: u64 },
+    #[fail(display = "id {} refers to a {}, not a {}", id, actual, expected)]
+    WrongGrammarKind {
+        id: u64,
+        expected: GrammarKind,
+        actual: GrammarKind,
+    },
+    #[fail(display = "invalid grammar: {}", reason)]
+    InvalidGrammar { reason: String },
+    #[fail(display = "no rule named {}", name)]
+    UnknownRule { name: String },
+    #[fail(display = "no list named {}", name)]
+    UnknownList { name: String },
+    #[fail(display = "not connected to the engine")]
+    EngineNotConnected,
+    #[fail(display = "engine call failed")]
+    EngineFailure { hresult: Option<i32> },
+    #[fail(display = "authentication failed")]
+    AuthenticationFailed,
+    #[fail(display = "authenticate with the auth method first")]
+    NotAuthenticated,
+    #[fail(display = "invalid configuration: {}", reason)]
+    InvalidConfig { reason: String },
+    #[fail(display = "client did not keep up with notifications")]
+    QueueOverflow,
+    #[fail(display = "no resumable session with that token")]
+    UnknownSession,
+    #[fail(display = "not allowed: {}", reason)]
+    Forbidden { reason: String },
+    #[fail(display = "the engine is not paused with token {}", token)]
+    UnknownPause { token: String },
+    #[fail(display = "no shared grammar named {}", name)]
+    UnknownSharedGrammar { name: String },
+    #[fail(display = "a different grammar is already shared as {}", name)]
+    SharedGrammarConflict { name: String },
+    #[fail(display = "protocol version {} is not supported", version)]
+    UnsupportedProtocolVersion { version: u64, supported: Vec<u64> },
+    #[fail(display = "a session can only be resumed before anything is loaded")]
+    SessionNotEmpty,
+    #[fail(display = "the grammar could not be loaded again after the engine restarted")]
+    GrammarLost,
+    #[fail(display = "the session already uses protocol version {}", version)]
+    ProtocolVersionFixed { version: u64 },
+}
+
+/// Code used for errors that do not fit any `ErrorKind`.
+const UNCLASSIFIED_ERROR: i64 = -1;
+
+impl ErrorKind {
+    /// The JSON-RPC error code for this kind of failure. These are part of
+    /// the protocol, so they should never be renumbered.
+    pub fn code(&self) -> i64 {
+        match *self {
+            ErrorKind::UnknownGrammar { .. } => -32001,
+            ErrorKind::InvalidGrammar { .. } => -32002,
+            ErrorKind::UnknownRule { .. } => -32003,
+            ErrorKind::UnknownList { .. } => -32004,
+            ErrorKind::EngineNotConnected => -32005,
+            ErrorKind::EngineFailure { .. } => -32006,
+            ErrorKind::WrongGrammarKind { .. } => -32007,
+            ErrorKind::AuthenticationFailed => -32008,
+            ErrorKind::NotAuthenticated => -32009,
+            ErrorKind::InvalidConfig { .. } => -32010,
+            ErrorKind::QueueOverflow => -32011,
+            ErrorKind::UnknownSession => -32012,
+            ErrorKind::Forbidden { .. } => -32013,
+            ErrorKind::UnknownPause { .. } => -32014,
+            ErrorKind::UnknownSharedGrammar { .. } => -32015,
+            ErrorKind::SharedGrammarConflict { .. } => -32016,
+            ErrorKind::UnsupportedProtocolVersion { .. } => -32017,
+            ErrorKind::SessionNotEmpty => -32018,
+            ErrorKind::GrammarLost => -32019,
+            ErrorKind::ProtocolVersionFixed { .. } => -32020,
+        }
+    }
+}
+
+impl MyError {
+    /// The first `ErrorKind` found in the cause chain, if any.
+    pub fn kind(&self) -> Option<&ErrorKind> {
+        self.0
+            .iter_chain()
+            .filter_map(|c| {
+                c.downcast_ref::<ErrorKind>().or_else(|| {
+                    c.downcast_ref::<Context<ErrorKind>>()
+                        .map(|c| c.get_context())
+                })
+            })
+            .next()
+    }
+
+    /// The error as it is reported to clients.
+    pub fn to_rpc_error(&self) -> RpcError {
+        let causes: Vec<String> = self.0.iter_chain().map(|c| c.to_string()).collect();
+
+        let (code, mut data) = match self.kind() {
+            Some(kind) => (
+                kind.code(),
+                serde_json::to_value(kind).unwrap_or_else(|_| json!({})),
+            ),
+            None => (UNCLASSIFIED_ERROR, json!({ "kind": "unclassified" })),
+        };
+
+        if let Value::Object(ref mut fields) = data {
+            fields.insert("causes".to_owned(), Value::from(causes));
+        }
+
         RpcError {
-            code: ErrorCode::ServerError(-1),
-            message: e.0.to_string(),
-            data: None,
+            code: ErrorCode::ServerError(code),
+            message: self.0.to_string(),
+            data: Some(data),
         }
     }
 }
+
+impl From<MyError> for RpcError {
+    fn from(e: MyError) -> RpcError {
+        e.to_rpc_error()
+    }
+}
@@ -0,0 +1,410 @@
+use crate::backend::{Backend, EngineEvent};
+use crate::errors::*;
+use crate::filter::{EventKind, Notifier};
+use crate::notifications::{create_engine_event_notification, EngineNotification, UtteranceClock};
+use crate::queue::NotificationSender;
+use crate::session::new_token;
+use log::{error, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+struct Subscriber {
+    id: u64,
+    holds_pauses: bool,
+    notifications: Notifier,
+}
+
+/// A pause the engine is waiting on. `waiting` holds the subscribers that
+/// still have to call `engine_resume`.
+struct Pause<C> {
+    token: String,
+    cookie: C,
+    waiting: BTreeSet<u64>,
+}
+
+/// When the pause that is being held runs out. One thread waits for it, no
+/// matter how often the engine pauses.
+struct Timer {
+    deadline: Mutex<Deadline>,
+    changed: Condvar,
+}
+
+struct Deadline {
+    pause: Option<(Instant, String)>,
+    stopped: bool,
+}
+
+struct HubState<R, C> {
+    counter: u64,
+    subscribers: BTreeMap<u64, Subscriber>,
+    /// Authenticated connections, which hear of every event.
+    watchers: Vec<NotificationSender>,
+    registration: Option<R>,
+    pause: Option<Pause<C>>,
+}
+
+/// The server's one registration for engine events. Every event is handled
+/// once and then passed on to each authenticated connection as an
+/// `engine_event`. Connections that called `engine_register` get an
+/// `engine_notification` for their registration instead.
+///
+/// When the engine pauses before a recognition, it is resumed as soon as
+/// every subscriber that asked to hold pauses has called `engine_resume`,
+/// or once `pause_timeout` has passed, whichever comes first. That gives
+/// clients a chance to update their grammars for the utterance.
+pub struct EngineEvents<B: Backend> {
+    engine: Arc<B>,
+    pause_timeout: Duration,
+    clock: Arc<UtteranceClock>,
+    timer: Arc<Timer>,
+    state: Mutex<HubState<B::Registration, B::PauseCookie>>,
+}
+
+impl<B: Backend> EngineEvents<B> {
+    pub fn new(engine: Arc<B>, pause_timeout: Duration) -> Arc<Self> {
+        let timer = Arc::new(Timer {
+            deadline: Mutex::new(Deadline {
+                pause: None,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let events = Arc::new(EngineEvents {
+            engine,
+            pause_timeout,
+            clock: UtteranceClock::new(),
+            timer: timer.clone(),
+            state: Mutex::new(HubState {
+                counter: 0,
+                subscribers: BTreeMap::new(),
+                watchers: Vec::new(),
+                registration: None,
+                pause: None,
+            }),
+        });
+
+        let weak = Arc::downgrade(&events);
+        thread::spawn(move || Self::run_timer(weak, timer));
+
+        events
+    }
+
+    /// Keeps track of when the engine last paused.
+    pub fn clock(&self) -> Arc<UtteranceClock> {
+        self.clock.clone()
+    }
+
+    /// Registers for engine events, which are handled from then on for as
+    /// long as the server runs.
+    pub fn start(self: &Arc<Self>) -> Result<()> {
+        let events = Arc::downgrade(self);
+        let registration = self.engine.register(move |e| {
+            if let Some(events) = events.upgrade() {
+                events.dispatch(e);
+            }
+        })?;
+
+        lock(&self.state).registration = Some(registration);
+        Ok(())
+    }
+
+    /// Sends every engine event to `watcher` as an `engine_event`, until it
+    /// is closed.
+    pub fn watch(&self, watcher: NotificationSender) {
+        let mut state = lock(&self.state);
+        state.watchers.retain(|w| !w.is_closed());
+        state.watchers.push(watcher);
+    }
+
+    /// Sends every engine event to `notifications` as an
+    /// `engine_notification` for `id`, until the subscription is dropped.
+    pub fn subscribe(
+        self: &Arc<Self>,
+        id: u64,
+        holds_pauses: bool,
+        notifications: Notifier,
+    ) -> Subscription<B> {
+        let mut state = lock(&self.state);
+
+        state.counter += 1;
+        let key = state.counter;
+        let subscriber = Subscriber {
+            id,
+            holds_pauses,
+            notifications,
+        };
+        state.subscribers.insert(key, subscriber);
+
+        Subscription {
+            key,
+            events: Arc::downgrade(self),
+        }
+    }
+
+    fn unsubscribe(&self, key: u64) {
+        let cookie = {
+            let mut state = lock(&self.state);
+            state.subscribers.remove(&key);
+            Self::release(&mut state, &[key])
+        };
+
+        if let Some(cookie) = cookie {
+            self.resume_engine(cookie);
+        }
+    }
+
+    /// Lets the engine continue once the subscribers in `keys` are done
+    /// with the pause identified by `token`.
+    pub fn resume(&self, keys: &[u64], token: &str) -> Result<()> {
+        let cookie = {
+            let mut state = lock(&self.state);
+
+            match state.pause {
+                Some(ref pause) if pause.token == token => {}
+                _ => {
+                    let token = token.to_owned();
+                    return Err(ErrorKind::UnknownPause { token }.into());
+                }
+            }
+
+            Self::release(&mut state, keys)
+        };
+
+        if let Some(cookie) = cookie {
+            self.resume_engine(cookie);
+        }
+
+        Ok(())
+    }
+
+    /// Stops waiting for `keys`, returning the cookie of the pause if
+    /// nobody else is holding it.
+    fn release(
+        state: &mut HubState<B::Registration, B::PauseCookie>,
+        keys: &[u64],
+    ) -> Option<B::PauseCookie> {
+        let done = match state.pause {
+            Some(ref mut pause) => {
+                for key in keys {
+                    pause.waiting.remove(key);
+                }
+                pause.waiting.is_empty()
+            }
+            None => false,
+        };
+
+        if done {
+            state.pause.take().map(|pause| pause.cookie)
+        } else {
+            None
+        }
+    }
+
+    fn resume_engine(&self, cookie: B::PauseCookie) {
+        if let Err(e) = self.engine.resume(cookie) {
+            error!("could not resume the engine: {}", e.0);
+        }
+    }
+
+    /// Holds the engine until the subscribers are done with the pause.
+    /// Returns the token of the pause.
+    fn pause(self: &Arc<Self>, cookie: B::PauseCookie) -> String {
+        let token = new_token();
+        self.clock.start();
+
+        let (previous, cookie) = {
+            let mut state = lock(&self.state);
+            let waiting: BTreeSet<u64> = state
+                .subscribers
+                .iter()
+                .filter(|&(_, s)| s.holds_pauses)
+                // nobody should wait on a client that will never hear of it
+                .filter(|&(_, s)| s.notifications.wants(s.id, Some(EventKind::Engine)))
+                .map(|(&key, _)| key)
+                .collect();
+
+            if waiting.is_empty() {
+                (None, Some(cookie))
+            } else {
+                let pause = Pause {
+                    token: token.clone(),
+                    cookie,
+                    waiting,
+                };
+                (state.pause.replace(pause), None)
+            }
+        };
+
+        // the engine should never pause twice without resuming in between,
+        // but it should not be left waiting forever if it does
+        if let Some(previous) = previous {
+            warn!("engine paused again before it was resumed");
+            self.resume_engine(previous.cookie);
+        }
+
+        match cookie {
+            Some(cookie) => self.resume_engine(cookie),
+            None => self.expire(token.clone()),
+        }
+
+        token
+    }
+
+    fn expire(&self, token: String) {
+        let mut deadline = lock(&self.timer.deadline);
+        deadline.pause = Some((Instant::now() + self.pause_timeout, token));
+        self.timer.changed.notify_one();
+    }
+
+    /// Resumes the engine whenever a pause is held for too long, until the
+    /// hub is dropped.
+    fn run_timer(events: Weak<Self>, timer: Arc<Timer>) {
+        if let Err(e) = B::prepare_thread() {
+            error!("pauses will not expire: {}", e.0);
+            return;
+        }
+
+        let mut deadline = lock(&timer.deadline);
+
+        loop {
+            if deadline.stopped {
+                return;
+            }
+
+            let now = Instant::now();
+            let at = deadline.pause.as_ref().map(|&(at, _)| at);
+            deadline = match at {
+                None => timer
+                    .changed
+                    .wait(deadline)
+                    .expect("attempt to lock poisoned mutex"),
+                Some(at) if at > now => {
+                    timer
+                        .changed
+                        .wait_timeout(deadline, at - now)
+                        .expect("attempt to lock poisoned mutex")
+                        .0
+                }
+                Some(_) => {
+                    let token = deadline.pause.take();
+                    drop(deadline);
+
+                    match (events.upgrade(), token) {
+                        (Some(events), Some((_, token))) => events.time_out(&token),
+                        (None, _) => return,
+                        _ => {}
+                    }
+
+                    lock(&timer.deadline)
+                }
+            };
+        }
+    }
+
+    fn time_out(&self, token: &str) {
+        let cookie = {
+            let mut state = lock(&self.state);
+
+            match state.pause {
+                Some(ref pause) if pause.token == token => {}
+                _ => return,
+            }
+
+            state.pause.take().map(|pause| pause.cookie)
+        };
+
+        if let Some(cookie) = cookie {
+            warn!("clients did not resume the engine in time, resuming it anyway");
+            self.resume_engine(cookie);
+        }
+    }
+
+    fn describe(self: &Arc<Self>, e: EngineEvent<B::PauseCookie>) -> Result<EngineNotification> {
+        let event = match e {
+            EngineEvent::Paused(cookie) => EngineNotification::Paused {
+                token: self.pause(cookie),
+            },
+            EngineEvent::MicrophoneState => EngineNotification::MicrophoneStateChanged {
+                state: self.engine.microphone_get_state()?,
+            },
+            EngineEvent::UserChanged => EngineNotification::UserChanged {
+                name: self.engine.get_current_user()?,
+            },
+        };
+
+        Ok(event)
+    }
+
+    fn dispatch(self: &Arc<Self>, e: EngineEvent<B::PauseCookie>) {
+        let event = match self.describe(e) {
+            Ok(event) => event,
+            Err(e) => {
+                error!("could not handle engine event: {}", e.0);
+                return;
+            }
+        };
+
+        let mut state = lock(&self.state);
+        for subscriber in state.subscribers.values() {
+            let kind = Some(EventKind::Engine);
+            subscriber
+                .notifications
+                .notify(subscriber.id, "engine_notification", kind, &event);
+        }
+
+        let notification = match create_engine_event_notification(&event) {
+            Ok(notification) => notification,
+            Err(e) => {
+                error!("{}", e.0);
+                return;
+            }
+        };
+
+        let state = &mut *state;
+        state.watchers.retain(|w| !w.is_closed());
+        for watcher in &state.watchers {
+            // registered connections already heard of it
+            let registered = state
+                .subscribers
+                .values()
+                .any(|s| s.notifications.sends_to(watcher));
+            if !registered {
+                watcher.send(Ok(notification.clone()));
+            }
+        }
+    }
+}
+
+impl<B: Backend> Drop for EngineEvents<B> {
+    fn drop(&mut self) {
+        lock(&self.timer.deadline).stopped = true;
+        self.timer.changed.notify_one();
+    }
+}
+
+/// A connection's interest in engine events.
+pub struct Subscription<B: Backend> {
+    key: u64,
+    events: Weak<EngineEvents<B>>,
+}
+
+impl<B: Backend> Subscription<B> {
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+}
+
+impl<B: Backend> Drop for Subscription<B> {
+    fn drop(&mut self) {
+        if let Some(events) = self.events.upgrade() {
+            events.unsubscribe(self.key);
+        }
+    }
+}
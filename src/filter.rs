@@ -0,0 +1,226 @@
+use crate::errors::*;
+use crate::notifications::{create_notification, UtteranceClock};
+use crate::queue::NotificationSender;
+use crate::rpc::RpcFilter;
+use crate::rpcimpl::GrammarIds;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use stentorian::engine::{GrammarEvent, Recognition};
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+/// What a notification is about, as far as subscriptions are concerned.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// The grammar recognized the utterance.
+    Recognition,
+    /// Nothing recognized the utterance.
+    Rejection,
+    /// Another grammar recognized the utterance.
+    OtherGrammar,
+    /// Something happened to the engine itself.
+    Engine,
+}
+
+pub const ALL_EVENT_KINDS: [EventKind; 4] = [
+    EventKind::Recognition,
+    EventKind::Rejection,
+    EventKind::OtherGrammar,
+    EventKind::Engine,
+];
+
+impl EventKind {
+    /// The kind of a grammar event, if it has one. Events without a kind
+    /// cannot be unsubscribed from.
+    pub fn of<T>(e: &GrammarEvent<T>) -> Option<Self> {
+        match e {
+            GrammarEvent::PhraseFinish(Recognition::Self_(_)) => Some(EventKind::Recognition),
+            GrammarEvent::PhraseFinish(Recognition::Other) => Some(EventKind::OtherGrammar),
+            GrammarEvent::PhraseFinish(Recognition::Reject) => Some(EventKind::Rejection),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+/// The notifications a connection unsubscribed from, by grammar id.
+/// Everything is subscribed to until the client says otherwise.
+pub struct Filter {
+    muted: Mutex<HashMap<u64, BTreeSet<EventKind>>>,
+}
+
+impl Filter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Filter {
+            muted: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn allows(&self, id: u64, kind: Option<EventKind>) -> bool {
+        match kind {
+            Some(kind) => lock(&self.muted)
+                .get(&id)
+                .map_or(true, |muted| !muted.contains(&kind)),
+            None => true,
+        }
+    }
+
+    fn subscribe(&self, id: u64, kinds: &[EventKind]) {
+        let mut muted = lock(&self.muted);
+
+        if let Some(kinds_muted) = muted.get_mut(&id) {
+            for kind in kinds {
+                kinds_muted.remove(kind);
+            }
+        }
+    }
+
+    fn unsubscribe(&self, id: u64, kinds: &[EventKind]) {
+        lock(&self.muted)
+            .entry(id)
+            .or_insert_with(BTreeSet::new)
+            .extend(kinds);
+    }
+
+    fn forget(&self, id: u64) {
+        lock(&self.muted).remove(&id);
+    }
+}
+
+/// Sends the notifications about a connection's grammars, leaving out
+/// the ones it is not subscribed to.
+#[derive(Clone)]
+pub struct Notifier {
+    sender: NotificationSender,
+    filter: Arc<Filter>,
+    clock: Arc<UtteranceClock>,
+}
+
+impl Notifier {
+    pub fn new(
+        sender: NotificationSender,
+        filter: Arc<Filter>,
+        clock: Arc<UtteranceClock>,
+    ) -> Self {
+        Notifier {
+            sender,
+            filter,
+            clock,
+        }
+    }
+
+    /// Whether this sends to the same connection as `sender`.
+    pub fn sends_to(&self, sender: &NotificationSender) -> bool {
+        self.sender.same_queue(sender)
+    }
+
+    /// Whether notifications of `kind` about `id` get through.
+    pub fn wants(&self, id: u64, kind: Option<EventKind>) -> bool {
+        self.filter.allows(id, kind)
+    }
+
+    pub fn notify<E>(&self, id: u64, method: &str, kind: Option<EventKind>, event: &E)
+    where
+        E: Serialize,
+    {
+        // only the end of an utterance has a kind, apart from engine events
+        let pause_time = match kind {
+            Some(EventKind::Engine) | None => None,
+            Some(_) => self.clock.get(),
+        };
+
+        let result = create_notification(&self.filter, id, method, kind, event, pause_time);
+        if let Some(result) = result {
+            self.sender.send(result);
+        }
+    }
+
+    /// Stops keeping track of `id`, which was unloaded.
+    pub fn forget(&self, id: u64) {
+        self.filter.forget(id);
+    }
+
+    /// Tells the client that grammar `id` could not be loaded again after
+    /// the engine restarted, and why.
+    pub fn grammar_lost(&self, id: u64, error: &MyError) {
+        self.notify(id, "grammar_reload_failed", None, &error.to_rpc_error());
+    }
+}
+
+pub struct RpcFilterImpl {
+    pub ids: Arc<GrammarIds>,
+    pub filter: Arc<Filter>,
+}
+
+impl RpcFilterImpl {
+    fn check(&self, id: u64) -> Result<()> {
+        if !self.ids.contains(id) {
+            return Err(ErrorKind::UnknownGrammar { id }.into());
+        }
+
+        Ok(())
+    }
+}
+
+impl RpcFilter for RpcFilterImpl {
+    fn subscribe(&self, id: u64, kinds: Option<Vec<EventKind>>) -> Result<()> {
+        self.check(id)?;
+        let kinds = kinds.unwrap_or_else(|| ALL_EVENT_KINDS.to_vec());
+        self.filter.subscribe(id, &kinds);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, id: u64, kinds: Option<Vec<EventKind>>) -> Result<()> {
+        self.check(id)?;
+        let kinds = kinds.unwrap_or_else(|| ALL_EVENT_KINDS.to_vec());
+        self.filter.unsubscribe(id, &kinds);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::UtteranceClock;
+    use crate::queue::{self, OverflowPolicy, QueueConfig};
+    use crate::rpc::RpcCatchall;
+    use crate::rpcimpl::{GrammarKind, RpcCatchallImpl, RpcHelper};
+    use crate::simulated::SimulatedEngine;
+
+    #[test]
+    fn unloading_forgets_unsubscriptions() {
+        let config = QueueConfig {
+            capacity: 16,
+            policy: OverflowPolicy::DropNewest,
+        };
+        let (sender, _receiver) = queue::queue(config);
+        let filter = Filter::new();
+        let ids = GrammarIds::new(None);
+        let notifications = Notifier::new(sender, filter.clone(), UtteranceClock::new());
+        let engine = Arc::new(SimulatedEngine::new());
+        let catchall = RpcCatchallImpl(RpcHelper::new(
+            engine,
+            notifications,
+            GrammarKind::Catchall,
+            ids.clone(),
+        ));
+        let rpc_filter = RpcFilterImpl {
+            ids,
+            filter: filter.clone(),
+        };
+
+        let id = catchall.load().unwrap();
+        rpc_filter.unsubscribe(id, None).unwrap();
+        assert!(!filter.allows(id, Some(EventKind::Recognition)));
+
+        catchall.unload(id).unwrap();
+        assert!(lock(&filter.muted).is_empty());
+    }
+}
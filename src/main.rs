@@ -1,140 +1,546 @@
-#![cfg(windows)]
-#![cfg(target_arch = "x86")]
-#![cfg(target_env = "msvc")]
+mod auth;
+mod backend;
+mod config;
+mod connection;
+mod connector;
+mod discover;
+#[cfg(feature = "dragon")]
+mod dragon;
 mod errors;
+mod events;
+mod filter;
 mod linecodec;
+mod mimic;
 mod notifications;
+mod protocol;
+mod queue;
+mod record;
+mod recovery;
+mod replay;
 mod rpc;
 mod rpcimpl;
+mod session;
+mod shared;
+mod shutdown;
+mod simulated;
+mod tls;
+mod validate;
+mod wsserver;
 
+use crate::auth::Authenticator;
+use crate::backend::Backend;
+use crate::config::{Config, LoggingConfig, PreloadConfig, TlsConfig};
+use crate::connection::{handle_connection, Shared};
+use crate::connector::Connector;
+#[cfg(feature = "dragon")]
+use crate::dragon::DragonEngine;
 use crate::errors::*;
+use crate::events::EngineEvents;
 use crate::linecodec::LineCodec;
-use crate::rpc::*;
-use crate::rpcimpl::*;
-use futures::stream;
-use futures::sync::mpsc;
-use futures::Future;
-use futures::Stream;
-use jsonrpc_core::IoHandler;
-use log::{error, info};
+use crate::protocol::Transport;
+use crate::queue::{OverflowPolicy, QueueConfig};
+use crate::record::{Recorder, Recording};
+use crate::session::SessionRegistry;
+use crate::shared::{Preloaded, SharedGrammars};
+use crate::shutdown::{Shutdown, ShutdownReason, Stopped};
+use crate::simulated::SimulatedEngine;
+use futures::{future, Future, Stream};
+use log::{error, info, warn};
+use std::env;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use std::{thread, time};
-use stentorian::engine::Engine;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stentorian::grammar::Grammar;
 use structopt::StructOpt;
 use tokio_codec::Framed;
 use tokio_core::net::TcpListener;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+
+/// How long connections get to close during a shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "server")]
 struct Opt {
+    /// Configuration file; defaults to stentorian/server.toml in the user's
+    /// configuration directory, if it exists
+    #[structopt(short = "c", long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Validate the configuration and exit
+    #[structopt(long = "check-config")]
+    check_config: bool,
     #[structopt(short = "H", long = "host")]
-    host: IpAddr,
+    host: Option<IpAddr>,
     #[structopt(short = "p", long = "port")]
-    port: u16,
+    port: Option<u16>,
+    /// Port to accept WebSocket connections on
+    #[structopt(long = "ws-port")]
+    ws_port: Option<u16>,
+    /// Origin of a web page that may connect over WebSocket, e.g.
+    /// http://localhost:8080; may be given more than once
+    #[structopt(long = "ws-origin")]
+    ws_origins: Vec<String>,
     #[structopt(short = "w", long = "wait")]
     wait_seconds: Option<u64>,
+    /// Log filter in RUST_LOG syntax, e.g. info or stentorian_server=debug
+    #[structopt(long = "log-level")]
+    log_level: Option<String>,
+    /// File containing the token clients have to pass to the auth method
+    #[structopt(long = "token-file", parse(from_os_str))]
+    token_file: Option<PathBuf>,
+    /// PEM certificate chain; enables TLS on the TCP listener
+    #[structopt(long = "tls-cert", parse(from_os_str), requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key belonging to --tls-cert
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Only accept TLS clients with a certificate signed by one of these CAs
+    #[structopt(long = "tls-client-ca", parse(from_os_str), requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// Maximum number of notifications buffered for a single client
+    /// [default: 1000]
+    #[structopt(long = "queue-size")]
+    queue_size: Option<usize>,
+    /// What to do when a client's notification queue is full: drop-oldest,
+    /// drop-newest or disconnect [default: drop-oldest]
+    #[structopt(long = "overflow-policy")]
+    overflow_policy: Option<OverflowPolicy>,
+    /// Seconds to keep the grammars of a disconnected client loaded, so it
+    /// can resume its session; 0 disables resumption [default: 0]
+    #[structopt(long = "session-grace")]
+    session_grace_seconds: Option<u64>,
+    /// Milliseconds to wait for clients holding a pause of the engine
+    /// before resuming it anyway [default: 1000]
+    #[structopt(long = "pause-timeout")]
+    pause_timeout_ms: Option<u64>,
+    /// Let clients shut the server down with the server_shutdown method
+    #[structopt(long = "allow-shutdown")]
+    allow_shutdown: bool,
+    /// Use the simulated engine even when Dragon support is compiled in
+    #[structopt(long = "simulate")]
+    simulate: bool,
+    /// Write every engine event and client request to this file
+    #[structopt(long = "record", parse(from_os_str), conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Feed a file written with --record to the simulated engine, print
+    /// what the clients were sent and exit
+    #[structopt(long = "replay", parse(from_os_str))]
+    replay: Option<PathBuf>,
 }
 
-fn create_handler(
-    engine: Arc<Engine>,
-    notifications: mpsc::UnboundedSender<Result<String>>,
-) -> IoHandler {
-    let mut handler = IoHandler::new();
-    let rpc_command = RpcCommandImpl(RpcHelper::new(engine.clone(), notifications.clone()));
-    let rpc_select = RpcSelectImpl(RpcHelper::new(engine.clone(), notifications.clone()));
-    let rpc_dictation = RpcDictationImpl(RpcHelper::new(engine.clone(), notifications.clone()));
-    let rpc_catchall = RpcCatchallImpl(RpcHelper::new(engine.clone(), notifications.clone()));
-    let rpc_engine = RpcEngineImpl(RpcHelper::new(engine.clone(), notifications));
-
-    handler.extend_with(rpc_command.to_delegate());
-    handler.extend_with(rpc_select.to_delegate());
-    handler.extend_with(rpc_dictation.to_delegate());
-    handler.extend_with(rpc_catchall.to_delegate());
-    handler.extend_with(rpc_engine.to_delegate());
-
-    handler
+/// Flags given on the command line take precedence over the file.
+fn apply_options(config: &mut Config, options: &Opt) {
+    let listen = &mut config.listen;
+    listen.host = options.host.unwrap_or(listen.host);
+    listen.port = options.port.or(listen.port);
+    listen.ws_port = options.ws_port.or(listen.ws_port);
+    listen.ws_origins.extend(options.ws_origins.iter().cloned());
+    listen.wait_seconds = options.wait_seconds.or(listen.wait_seconds);
+
+    if let Some(ref level) = options.log_level {
+        config.logging.level = level.clone();
+    }
+
+    if let Some(ref path) = options.token_file {
+        config.auth.token_file = Some(path.clone());
+    }
+
+    if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+        config.tls = Some(TlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: options.tls_client_ca.clone(),
+        });
+    }
+
+    config.admin.allow_shutdown |= options.allow_shutdown;
+
+    let limits = &mut config.limits;
+    limits.queue_size = options.queue_size.unwrap_or(limits.queue_size);
+    limits.overflow_policy = options.overflow_policy.unwrap_or(limits.overflow_policy);
+    limits.session_grace_seconds = options
+        .session_grace_seconds
+        .unwrap_or(limits.session_grace_seconds);
+    limits.pause_timeout_ms = options.pause_timeout_ms.unwrap_or(limits.pause_timeout_ms);
 }
 
-fn run_server(options: Opt) -> Result<()> {
-    let mut core = Core::new()?;
-    let handle = core.handle();
+fn init_logging(config: &LoggingConfig) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse(&config.level);
 
-    let addr = SocketAddr::new(options.host, options.port);
-    let listener = TcpListener::bind(&addr, &handle)?;
+    if let Ok(filter) = env::var("RUST_LOG") {
+        builder.parse(&filter);
+    }
 
-    info!("listening for connections on {}", addr);
+    builder.init();
+}
 
-    let engine = Arc::new(Engine::connect()?);
+/// Everything from the configuration that has to be read from disk. Doing
+/// this before connecting to the engine means mistakes show up right away.
+struct Prepared {
+    authenticator: Authenticator,
+    tls: Option<TlsAcceptor>,
+    grammars: Vec<(Grammar, PreloadConfig)>,
+}
 
-    let server = listener.incoming().for_each(|(sock, _)| {
-        info!("new connection");
-        // let framed = AsyncRead::framed(sock, LineCodec);
-        let framed = Framed::new(sock, LineCodec);
-        let (responses, requests) = framed.split();
+fn prepare(config: &Config) -> Result<Prepared> {
+    let authenticator = match config.auth.token_file {
+        Some(ref path) => Authenticator::from_file(path)?,
+        None => Authenticator::disabled(),
+    };
 
-        let (notifications_tx, notifications_rx) = mpsc::unbounded();
-        let notifications_rx = notifications_rx
-            .map_err(|()| panic!("channel receive should never fail"))
-            .and_then(|r| r)
-            .map(|x| Some(x))
-            .chain(stream::once(Ok(None)));
+    let tls = match config.tls {
+        Some(ref tls) => Some(tls::load_acceptor(
+            &tls.cert,
+            &tls.key,
+            tls.client_ca.as_ref().map(|p| p.as_path()),
+        )?),
+        None => None,
+    };
 
-        let handler = create_handler(engine.clone(), notifications_tx);
+    let grammars = config
+        .grammars
+        .iter()
+        .map(|preload| Ok((preload.read_grammar()?, preload.clone())))
+        .collect::<Result<_>>()?;
 
-        let request_results = requests
-            .and_then(move |r| {
-                handler
-                    .handle_request(&r)
-                    .map_err(|()| panic!("handle_request should never fail"))
-            })
-            .filter_map(|x| x)
-            .from_err()
-            .map(|x| Some(x))
-            .chain(stream::once(Ok(None)));
-
-        let merged = request_results
-            .select(notifications_rx)
-            .take_while(|x| Ok(x.is_some()))
-            .filter_map(|x| x);
-
-        let handle_connection = merged.forward(responses).then(|r: Result<_>| {
-            match r {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("{}", e.0);
+    Ok(Prepared {
+        authenticator,
+        tls,
+        grammars,
+    })
+}
+
+/// Loads the grammars from the configuration file as shared grammars
+/// owned by the server, named after their file unless they are given a
+/// name. Clients can attach to them like to any other shared grammar.
+fn preload<B: Backend>(
+    shared: &SharedGrammars<B>,
+    grammars: &[(Grammar, PreloadConfig)],
+) -> Result<Vec<Preloaded<B>>> {
+    let mut preloaded = Vec::new();
+
+    for (grammar, preload) in grammars {
+        let name = preload
+            .name
+            .clone()
+            .unwrap_or_else(|| preload.file.display().to_string());
+
+        preloaded.push(shared.preload(&name, grammar, &preload.rules)?);
+        info!("preloaded grammar {}", name);
+    }
+
+    Ok(preloaded)
+}
+
+fn serve_socket<B, S>(handle: &Handle, shared: &Shared<B>, peer: SocketAddr, sock: S)
+where
+    B: Backend,
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    // let framed = AsyncRead::framed(sock, LineCodec);
+    let framed = Framed::new(sock, LineCodec);
+    let (responses, requests) = framed.split();
+
+    handle.spawn(handle_connection(shared, handle, peer, requests, responses));
+}
+
+fn listen_tcp<B: Backend>(
+    addr: &SocketAddr,
+    handle: &Handle,
+    shared: Arc<Shared<B>>,
+    tls: Option<TlsAcceptor>,
+) -> Result<impl Future<Item = (), Error = MyError>> {
+    let listener = TcpListener::bind(addr, handle)?;
+
+    match tls {
+        Some(_) => info!("listening for TLS connections on {}", addr),
+        None => info!("listening for connections on {}", addr),
+    }
+
+    let handle = handle.clone();
+    let server = listener
+        .incoming()
+        .from_err()
+        .for_each(move |(sock, peer)| {
+            info!("new connection from {}", peer);
+
+            match tls {
+                Some(ref acceptor) => {
+                    let spawn_handle = handle.clone();
+                    let shared = shared.clone();
+
+                    let accept = acceptor.accept(sock).then(move |r| {
+                        match r {
+                            Ok(stream) => serve_socket(&spawn_handle, &shared, peer, stream),
+                            Err(e) => error!("TLS handshake with {} failed: {}", peer, e),
+                        }
+
+                        Ok(())
+                    });
+
+                    handle.spawn(accept);
                 }
+                None => serve_socket(&handle, &shared, peer, sock),
             }
 
-            info!("connection closed");
             Ok(())
         });
 
-        handle.spawn(handle_connection);
-        Ok(())
+    Ok(server)
+}
+
+fn transports(config: &Config) -> Vec<Transport> {
+    let mut transports = Vec::new();
+
+    if config.listen.port.is_some() {
+        match config.tls {
+            Some(_) => transports.push(Transport::Tls),
+            None => transports.push(Transport::Tcp),
+        }
+    }
+    if config.listen.ws_port.is_some() {
+        transports.push(Transport::Websocket);
+    }
+
+    transports
+}
+
+fn run_server<B, F>(
+    config: &Config,
+    prepared: Prepared,
+    recorder: Option<Arc<Recorder>>,
+    connect: F,
+) -> Result<Stopped>
+where
+    B: Backend,
+    F: FnMut() -> Result<B> + Send + 'static,
+{
+    // the requests are handled on this thread
+    B::prepare_thread()?;
+
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let engine = Connector::new();
+
+    let shared = Arc::new(Shared {
+        events: EngineEvents::new(
+            engine.clone(),
+            Duration::from_millis(config.limits.pause_timeout_ms),
+        ),
+        grammars: SharedGrammars::new(engine.clone()),
+        engine,
+        authenticator: Arc::new(prepared.authenticator),
+        queue: QueueConfig {
+            capacity: config.limits.queue_size,
+            policy: config.limits.overflow_policy,
+        },
+        sessions: SessionRegistry::new(Duration::from_secs(config.limits.session_grace_seconds)),
+        shutdown: Shutdown::new(),
+        allow_shutdown: config.admin.allow_shutdown,
+        recorder,
+        transports: transports(config),
+    });
+
+    let events = shared.events.clone();
+    shared.engine.when_connected(move || {
+        if let Err(e) = events.start() {
+            error!("could not register for engine events: {}", e.0);
+        }
     });
 
-    core.run(server)?;
+    let grammars = prepared.grammars;
+    let preloaded = Arc::new(Mutex::new(Vec::new()));
+    let preloaded_by_hook = preloaded.clone();
+    let shared_grammars = shared.grammars.clone();
+    shared
+        .engine
+        .when_connected(move || match preload(&shared_grammars, &grammars) {
+            Ok(held) => {
+                *preloaded_by_hook
+                    .lock()
+                    .expect("attempt to lock poisoned mutex") = held
+            }
+            Err(e) => error!("could not preload grammars: {}", e.0),
+        });
+
+    // clients can connect while the engine is still coming up
+    let delay = Duration::from_secs(config.listen.wait_seconds.unwrap_or(0));
+    shared.engine.start(delay, connect);
+    let mut servers: Vec<Box<dyn Future<Item = (), Error = MyError>>> = Vec::new();
+
+    if let Some(port) = config.listen.port {
+        let addr = SocketAddr::new(config.listen.host, port);
+        servers.push(Box::new(listen_tcp(
+            &addr,
+            &handle,
+            shared.clone(),
+            prepared.tls,
+        )?));
+    }
+
+    if let Some(port) = config.listen.ws_port {
+        let addr = SocketAddr::new(config.listen.host, port);
+        let origins = config.listen.ws_origins.clone();
+        servers.push(Box::new(wsserver::listen(
+            &addr,
+            &handle,
+            shared.clone(),
+            origins,
+        )?));
+    }
+
+    watch_signals(&handle, shared.shutdown.clone());
+
+    // stop accepting connections as soon as shutdown is triggered
+    let listening = future::join_all(servers).map(|_| None);
+    let triggered = shared.shutdown.triggered().map(Some);
+    let reason = core
+        .run(listening.select(triggered))
+        .map(|(reason, _)| reason)
+        .map_err(|(e, _)| e)?;
+
+    let reason = match reason {
+        Some(reason) => reason,
+        None => return Ok(Stopped::Clean),
+    };
+
+    // give the clients a moment to receive the notification, and their
+    // connections to unload everything they had loaded
+    let timeout = Timeout::new(SHUTDOWN_TIMEOUT, &handle)?;
+    let drained = shared
+        .shutdown
+        .drained()
+        .map(|()| true)
+        .select(timeout.from_err().map(|()| false))
+        .map(|(drained, _)| drained)
+        .map_err(|(e, _)| e);
+
+    let stopped = if core.run(drained)? {
+        Stopped::Clean
+    } else {
+        warn!("not every connection closed in time, closing them anyway");
+        Stopped::Forced
+    };
+
+    shared.sessions.clear();
+    preloaded
+        .lock()
+        .expect("attempt to lock poisoned mutex")
+        .clear();
+    drop(core);
+
+    info!("server shut down: {}", reason.describe());
+    Ok(stopped)
+}
+
+/// Runs the server, recording everything that happens if `recorder` is
+/// given.
+fn run_engine<B, F>(
+    config: &Config,
+    prepared: Prepared,
+    recorder: Option<Arc<Recorder>>,
+    mut connect: F,
+) -> Result<Stopped>
+where
+    B: Backend,
+    F: FnMut() -> Result<B> + Send + 'static,
+{
+    match recorder {
+        Some(recorder) => {
+            info!("recording engine events and requests");
+            let engine_recorder = recorder.clone();
+            run_server(config, prepared, Some(recorder), move || {
+                Ok(Recording::new(connect()?, engine_recorder.clone()))
+            })
+        }
+        None => run_server(config, prepared, None, connect),
+    }
+}
+
+/// Shuts down on Ctrl+C, or Ctrl+Break in a Windows console, and on
+/// SIGTERM elsewhere.
+fn watch_signals(handle: &Handle, shutdown: Arc<Shutdown>) {
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream();
+
+    #[cfg(unix)]
+    let signals = {
+        use tokio_signal::unix::{Signal, SIGTERM};
 
-    Ok(())
+        let sigterm = Signal::new(SIGTERM).flatten_stream().map(|_| ());
+        ctrl_c.select(sigterm)
+    };
+    #[cfg(not(unix))]
+    let signals = ctrl_c;
+
+    handle.spawn(signals.into_future().then(move |r| {
+        match r {
+            Ok(_) => shutdown.trigger(ShutdownReason::Signal),
+            Err((e, _)) => error!("could not listen for signals: {}", e),
+        }
+
+        Ok(())
+    }));
 }
 
-fn serve() -> Result<()> {
-    env_logger::init();
+fn serve() -> Result<Stopped> {
     let options = Opt::from_args();
 
-    if let Some(s) = options.wait_seconds {
-        info!("sleeping for {} seconds before connecting to Dragon", s);
-        thread::sleep(time::Duration::from_secs(s))
+    let mut config = Config::load(options.config.as_ref().map(|p| p.as_path()))?;
+    apply_options(&mut config, &options);
+    config.validate(options.replay.is_none())?;
+
+    let prepared = prepare(&config)?;
+
+    if options.check_config {
+        println!("configuration is valid");
+        return Ok(Stopped::Clean);
+    }
+
+    init_logging(&config.logging);
+
+    if let Some(ref path) = options.replay {
+        replay::replay(&config, &prepared.grammars, path)?;
+        return Ok(Stopped::Clean);
+    }
+
+    if !prepared.authenticator.is_required() {
+        warn!(
+            "no token file configured, so any local process can use the server \
+             without authenticating"
+        );
+    }
+
+    let recorder = match options.record {
+        Some(ref path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+
+    if let Some(s) = config.listen.wait_seconds {
+        info!("waiting {} seconds before connecting to the engine", s);
+    }
+
+    #[cfg(feature = "dragon")]
+    {
+        if !options.simulate {
+            return run_engine(&config, prepared, recorder, DragonEngine::connect);
+        }
     }
 
-    stentorian::initialize()?;
-    run_server(options)
+    info!("using simulated engine");
+    run_engine(&config, prepared, recorder, || Ok(SimulatedEngine::new()))
 }
 
 pub fn main() {
-    if let Err(e) = serve() {
-        println!("{}", e.0);
+    match serve() {
+        Ok(stopped) => process::exit(stopped.exit_code()),
+        Err(e) => {
+            println!("{}", e.0);
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,512 @@
+use crate::backend::*;
+use crate::errors::Result;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use stentorian::engine::{
+    CatchallGrammarEvent, CommandGrammarEvent, DictationGrammarEvent, GrammarEvent, Recognition,
+    SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+use stentorian::resultparser::Matcher;
+
+type Callback<E> = Arc<dyn Fn(E) + Sync + Send>;
+
+enum Entry {
+    Command {
+        grammar: Grammar,
+        active_rules: HashSet<String>,
+        /// Matches the active rules only, if there are any.
+        matcher: Option<Matcher>,
+        callback: Callback<CommandGrammarEvent>,
+    },
+    Select {
+        select_words: Vec<String>,
+        through_words: Vec<String>,
+        /// The words of the select text.
+        text: Vec<String>,
+        active: bool,
+        callback: Callback<SelectGrammarEvent>,
+    },
+    Dictation {
+        active: bool,
+        callback: Callback<DictationGrammarEvent>,
+    },
+    Catchall {
+        active: bool,
+        callback: Callback<CatchallGrammarEvent>,
+    },
+}
+
+struct Entries {
+    counter: u64,
+    items: HashMap<u64, Entry>,
+}
+
+/// Keeps a copy of every grammar callback handed to a backend, so that a
+/// recognition can be injected without going through the microphone.
+pub struct MimicRegistry {
+    entries: Mutex<Entries>,
+}
+
+fn recognized<T>(words: T) -> GrammarEvent<T> {
+    GrammarEvent::PhraseFinish(Recognition::Self_(words))
+}
+
+fn other<T>() -> GrammarEvent<T> {
+    GrammarEvent::PhraseFinish(Recognition::Other)
+}
+
+fn rejected<T>() -> GrammarEvent<T> {
+    GrammarEvent::PhraseFinish(Recognition::Reject)
+}
+
+/// A matcher for which only the active rules are top-level rules. The
+/// other rules stay in the grammar, since active rules can refer to them.
+fn active_matcher(grammar: &Grammar, active_rules: &HashSet<String>) -> Option<Matcher> {
+    if active_rules.is_empty() {
+        return None;
+    }
+
+    let mut grammar = grammar.clone();
+    for rule in &mut grammar.rules {
+        rule.exported = rule.exported && active_rules.contains(&rule.name);
+    }
+
+    Some(Matcher::new(&grammar))
+}
+
+/// Whether `run` occurs in `text` as consecutive words.
+fn contains_run(text: &[String], run: &[String]) -> bool {
+    !run.is_empty() && text.windows(run.len()).any(|w| w == run)
+}
+
+/// Whether `words` is a select word followed by words from the text, or
+/// by words from the text, a through word and more words from the text.
+fn select_matches(
+    select_words: &[String],
+    through_words: &[String],
+    text: &[String],
+    words: &[String],
+) -> bool {
+    let (first, rest) = match words.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    if !select_words.contains(first) {
+        return false;
+    }
+
+    contains_run(text, rest)
+        || rest.iter().enumerate().any(|(i, word)| {
+            through_words.contains(word)
+                && contains_run(text, &rest[..i])
+                && contains_run(text, &rest[i + 1..])
+        })
+}
+
+impl MimicRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(MimicRegistry {
+            entries: Mutex::new(Entries {
+                counter: 0,
+                items: HashMap::new(),
+            }),
+        })
+    }
+
+    fn entries(&self) -> MutexGuard<Entries> {
+        self.entries.lock().expect("attempt to lock poisoned mutex")
+    }
+
+    fn insert<C>(self: &Arc<Self>, control: C, entry: Entry) -> Mimicked<C> {
+        let mut entries = self.entries();
+        entries.counter += 1;
+        let key = entries.counter;
+        entries.items.insert(key, entry);
+
+        Mimicked {
+            inner: control,
+            key,
+            registry: Arc::downgrade(self),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Entry)>(&self, key: u64, f: F) {
+        if let Some(entry) = self.entries().items.get_mut(&key) {
+            f(entry);
+        }
+    }
+
+    /// Loads a command grammar through `load`, recording the callback so it
+    /// can later be reached by `mimic`.
+    pub fn command_grammar_load<C, F, L>(
+        self: &Arc<Self>,
+        grammar: &Grammar,
+        callback: F,
+        load: L,
+    ) -> Result<Mimicked<C>>
+    where
+        F: Fn(CommandGrammarEvent) + Sync + Send + 'static,
+        L: FnOnce(Box<dyn Fn(CommandGrammarEvent) + Sync + Send>) -> Result<C>,
+    {
+        let callback: Callback<CommandGrammarEvent> = Arc::new(callback);
+        let forward = callback.clone();
+        let control = load(Box::new(move |e| forward(e)))?;
+        let entry = Entry::Command {
+            grammar: grammar.clone(),
+            active_rules: HashSet::new(),
+            matcher: None,
+            callback,
+        };
+
+        Ok(self.insert(control, entry))
+    }
+
+    pub fn select_grammar_load<C, F, L>(
+        self: &Arc<Self>,
+        select_words: &[String],
+        through_words: &[String],
+        callback: F,
+        load: L,
+    ) -> Result<Mimicked<C>>
+    where
+        F: Fn(SelectGrammarEvent) + Sync + Send + 'static,
+        L: FnOnce(Box<dyn Fn(SelectGrammarEvent) + Sync + Send>) -> Result<C>,
+    {
+        let callback: Callback<SelectGrammarEvent> = Arc::new(callback);
+        let forward = callback.clone();
+        let control = load(Box::new(move |e| forward(e)))?;
+        let entry = Entry::Select {
+            select_words: select_words.to_vec(),
+            through_words: through_words.to_vec(),
+            text: Vec::new(),
+            active: false,
+            callback,
+        };
+
+        Ok(self.insert(control, entry))
+    }
+
+    pub fn dictation_grammar_load<C, F, L>(
+        self: &Arc<Self>,
+        callback: F,
+        load: L,
+    ) -> Result<Mimicked<C>>
+    where
+        F: Fn(DictationGrammarEvent) + Sync + Send + 'static,
+        L: FnOnce(Box<dyn Fn(DictationGrammarEvent) + Sync + Send>) -> Result<C>,
+    {
+        let callback: Callback<DictationGrammarEvent> = Arc::new(callback);
+        let forward = callback.clone();
+        let control = load(Box::new(move |e| forward(e)))?;
+        let entry = Entry::Dictation {
+            active: false,
+            callback,
+        };
+
+        Ok(self.insert(control, entry))
+    }
+
+    pub fn catchall_grammar_load<C, F, L>(
+        self: &Arc<Self>,
+        callback: F,
+        load: L,
+    ) -> Result<Mimicked<C>>
+    where
+        F: Fn(CatchallGrammarEvent) + Sync + Send + 'static,
+        L: FnOnce(Box<dyn Fn(CatchallGrammarEvent) + Sync + Send>) -> Result<C>,
+    {
+        let callback: Callback<CatchallGrammarEvent> = Arc::new(callback);
+        let forward = callback.clone();
+        let control = load(Box::new(move |e| forward(e)))?;
+        let entry = Entry::Catchall {
+            active: false,
+            callback,
+        };
+
+        Ok(self.insert(control, entry))
+    }
+
+    /// Delivers `words` to the loaded grammars as if they had been spoken.
+    ///
+    /// The first command grammar with an active rule that matches the words
+    /// gets the recognition, otherwise the first active select grammar whose
+    /// select text the words pick out, otherwise the first active dictation
+    /// grammar. The remaining command grammars are told that
+    /// another grammar won, or that the utterance was rejected if nobody
+    /// did. Active catchall grammars always see the words. Returns whether
+    /// any grammar recognized the utterance.
+    pub fn mimic(&self, words: &[String]) -> bool {
+        // snapshot the callbacks so none of them run while the registry is
+        // locked
+        let mut commands = Vec::new();
+        let mut winner = None;
+        let mut select = None;
+        let mut dictation = None;
+        let mut catchalls = Vec::new();
+
+        {
+            let entries = self.entries();
+            let mut keys: Vec<&u64> = entries.items.keys().collect();
+            keys.sort();
+
+            for key in keys {
+                match &entries.items[key] {
+                    Entry::Command {
+                        matcher, callback, ..
+                    } => {
+                        let matcher = match matcher {
+                            Some(matcher) => matcher,
+                            None => continue,
+                        };
+
+                        if winner.is_none() && matcher.perform_match(words).is_some() {
+                            winner = Some(callback.clone());
+                        } else {
+                            commands.push(callback.clone());
+                        }
+                    }
+                    Entry::Select {
+                        select_words,
+                        through_words,
+                        text,
+                        active,
+                        callback,
+                    } => {
+                        if *active
+                            && select.is_none()
+                            && select_matches(select_words, through_words, text, words)
+                        {
+                            select = Some(callback.clone());
+                        }
+                    }
+                    Entry::Dictation { active, callback } => {
+                        if *active && dictation.is_none() {
+                            dictation = Some(callback.clone());
+                        }
+                    }
+                    Entry::Catchall { active, callback } => {
+                        if *active {
+                            catchalls.push(callback.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        let words = words.to_vec();
+        let recognized_by_any = if let Some(callback) = winner {
+            callback(recognized(words.clone()));
+            true
+        } else if let Some(callback) = select {
+            callback(recognized(words.clone()));
+            true
+        } else if let Some(callback) = dictation {
+            callback(recognized(words.clone()));
+            true
+        } else {
+            false
+        };
+
+        for callback in commands {
+            if recognized_by_any {
+                callback(other());
+            } else {
+                callback(rejected());
+            }
+        }
+
+        for callback in catchalls {
+            callback(recognized(words.clone()));
+        }
+
+        recognized_by_any
+    }
+
+    /// Delivers `event` to the grammar that was loaded as the `key`th one,
+    /// counting from 1. Returns whether that grammar is still loaded.
+    pub fn inject(&self, key: u64, event: GrammarEvent<Vec<String>>) -> bool {
+        let callback: Callback<GrammarEvent<Vec<String>>> = {
+            let entries = self.entries();
+
+            match entries.items.get(&key) {
+                Some(Entry::Command { callback, .. }) => callback.clone(),
+                Some(Entry::Select { callback, .. }) => callback.clone(),
+                Some(Entry::Dictation { callback, .. }) => callback.clone(),
+                Some(Entry::Catchall { callback, .. }) => callback.clone(),
+                None => return false,
+            }
+        };
+
+        callback(event);
+        true
+    }
+}
+
+/// A control whose activation state is mirrored in a `MimicRegistry`. The
+/// grammar is removed from the registry when the control is dropped.
+pub struct Mimicked<C> {
+    inner: C,
+    key: u64,
+    registry: Weak<MimicRegistry>,
+}
+
+impl<C> Mimicked<C> {
+    fn update<F: FnOnce(&mut Entry)>(&self, f: F) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry.update(self.key, f);
+        }
+    }
+
+    fn set_rule_active(&self, name: &str, value: bool) {
+        self.update(|entry| {
+            if let Entry::Command {
+                grammar,
+                active_rules,
+                matcher,
+                ..
+            } = entry
+            {
+                if value {
+                    active_rules.insert(name.to_owned());
+                } else {
+                    active_rules.remove(name);
+                }
+                *matcher = active_matcher(grammar, active_rules);
+            }
+        });
+    }
+
+    fn set_active(&self, value: bool) {
+        self.update(|entry| match entry {
+            Entry::Select { active, .. }
+            | Entry::Dictation { active, .. }
+            | Entry::Catchall { active, .. } => *active = value,
+            Entry::Command { .. } => {}
+        });
+    }
+}
+
+impl<C> Drop for Mimicked<C> {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry.entries().items.remove(&self.key);
+        }
+    }
+}
+
+impl<C: CommandControl> CommandControl for Mimicked<C> {
+    fn rule_activate(&self, name: &str) -> Result<()> {
+        self.inner.rule_activate(name)?;
+        self.set_rule_active(name, true);
+        Ok(())
+    }
+
+    fn rule_deactivate(&self, name: &str) -> Result<()> {
+        self.inner.rule_deactivate(name)?;
+        self.set_rule_active(name, false);
+        Ok(())
+    }
+
+    fn list_append(&self, name: &str, word: &str) -> Result<()> {
+        self.inner.list_append(name, word)
+    }
+
+    fn list_remove(&self, name: &str, word: &str) -> Result<()> {
+        self.inner.list_remove(name, word)
+    }
+
+    fn list_clear(&self, name: &str) -> Result<()> {
+        self.inner.list_clear(name)
+    }
+}
+
+impl<C: SelectControl> Mimicked<C> {
+    /// Copies the select text after it changed.
+    fn sync_text(&self) -> Result<()> {
+        let words: Vec<String> = self
+            .inner
+            .text_get()?
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+
+        self.update(|entry| {
+            if let Entry::Select { text, .. } = entry {
+                *text = words;
+            }
+        });
+        Ok(())
+    }
+}
+
+impl<C: SelectControl> SelectControl for Mimicked<C> {
+    fn activate(&self) -> Result<()> {
+        self.inner.activate()?;
+        self.set_active(true);
+        Ok(())
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        self.inner.deactivate()?;
+        self.set_active(false);
+        Ok(())
+    }
+
+    fn text_set(&self, text: &str) -> Result<()> {
+        self.inner.text_set(text)?;
+        self.sync_text()
+    }
+
+    fn text_change(&self, start: u32, stop: u32, text: &str) -> Result<()> {
+        self.inner.text_change(start, stop, text)?;
+        self.sync_text()
+    }
+
+    fn text_delete(&self, start: u32, stop: u32) -> Result<()> {
+        self.inner.text_delete(start, stop)?;
+        self.sync_text()
+    }
+
+    fn text_insert(&self, start: u32, text: &str) -> Result<()> {
+        self.inner.text_insert(start, text)?;
+        self.sync_text()
+    }
+
+    fn text_get(&self) -> Result<String> {
+        self.inner.text_get()
+    }
+}
+
+impl<C: DictationControl> DictationControl for Mimicked<C> {
+    fn activate(&self) -> Result<()> {
+        self.inner.activate()?;
+        self.set_active(true);
+        Ok(())
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        self.inner.deactivate()?;
+        self.set_active(false);
+        Ok(())
+    }
+
+    fn context_set(&self, context: &str) -> Result<()> {
+        self.inner.context_set(context)
+    }
+}
+
+impl<C: CatchallControl> CatchallControl for Mimicked<C> {
+    fn activate(&self) -> Result<()> {
+        self.inner.activate()?;
+        self.set_active(true);
+        Ok(())
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        self.inner.deactivate()?;
+        self.set_active(false);
+        Ok(())
+    }
+}
@@ -1,56 +1,240 @@
+use crate::connector::EngineStatus;
 use crate::errors::*;
+use crate::filter::{EventKind, Filter};
 use jsonrpc_core::{Notification, Params, Version};
-use serde::Serialize;
-use serde_json;
-use stentorian::engine::Engine;
-use stentorian::engine::{EngineEvent, MicrophoneState};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use stentorian::engine::MicrophoneState;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum EngineNotification {
-    Paused,
-    MicrophoneStateChanged { state: MicrophoneState },
-    UserChanged { name: Option<String> },
+    /// The engine waits for `engine_resume` with `token` before it starts
+    /// recognizing.
+    Paused {
+        token: String,
+    },
+    MicrophoneStateChanged {
+        #[schemars(with = "crate::discover::mirrors::MicrophoneStateDef")]
+        state: MicrophoneState,
+    },
+    UserChanged {
+        name: Option<String>,
+    },
 }
 
-impl EngineNotification {
-    pub fn from_event(engine: &Engine, e: EngineEvent) -> Result<Self> {
-        match e {
-            EngineEvent::Paused(cookie) => {
-                engine.resume(cookie)?;
+/// Sent after an engine restart, once every grammar was loaded again.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RecoveredSummary {
+    /// How often the server connected to the engine.
+    pub generation: u64,
+}
 
-                let event = EngineNotification::Paused;
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ShutdownDetails {
+    pub reason: String,
+}
 
-                Ok(event)
-            }
-            EngineEvent::MicrophoneState => {
-                let state = engine.microphone_get_state()?;
-                let event = EngineNotification::MicrophoneStateChanged { state };
+/// What notifications carry besides their event, from protocol version 2
+/// on.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Meta {
+    /// The sequence number of the notification on its connection.
+    pub seq: u64,
+    /// When the notification was created.
+    pub time: u64,
+    /// When the engine paused before the utterance that ended, for grammar
+    /// events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pause_time: Option<u64>,
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn timestamp() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+/// When the engine last paused, by the server's clock. The engine pauses at
+/// the start of every utterance, so this is roughly when the utterance
+/// being recognized started. It is only known once the engine paused.
+pub struct UtteranceClock(Mutex<Option<u64>>);
+
+impl UtteranceClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(UtteranceClock(Mutex::new(None)))
+    }
+
+    fn started(&self) -> MutexGuard<Option<u64>> {
+        self.0.lock().expect("attempt to lock poisoned mutex")
+    }
 
-                Ok(event)
+    pub fn start(&self) {
+        *self.started() = Some(timestamp());
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        *self.started()
+    }
+}
+
+/// How the parameters of notifications are laid out, which depends on the
+/// protocol version of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationFormat {
+    /// `[grammar_id, event]`, with the grammar id left out for
+    /// notifications that are not about a grammar. This is the layout of
+    /// protocol version 1, which has no room for `Meta`.
+    Positional,
+    /// `{"grammar_id": .., "event": .., "seq": .., ...}`, where the event is
+    /// named after what it holds for notifications that are not about a
+    /// grammar.
+    Named,
+}
+
+/// A notification that has not been sent yet. It is given its sequence
+/// number once it is queued for a connection, and only serialized when it
+/// is about to be sent.
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    method: String,
+    grammar_id: Option<u64>,
+    event_name: &'static str,
+    event: Value,
+    time: u64,
+    pause_time: Option<u64>,
+}
+
+impl Outgoing {
+    fn new(method: &str, grammar_id: Option<u64>, event: Value) -> Self {
+        Outgoing {
+            method: method.to_owned(),
+            grammar_id,
+            event_name: "event",
+            event,
+            time: timestamp(),
+            pause_time: None,
+        }
+    }
+
+    /// Serializes the notification as the `seq`th one sent to a
+    /// connection. Only the named format carries the sequence number and
+    /// the times.
+    pub fn render(&self, seq: u64, format: NotificationFormat) -> Result<String> {
+        let params = match format {
+            NotificationFormat::Positional => {
+                let mut params = Vec::new();
+                params.extend(self.grammar_id.map(Value::from));
+                params.push(self.event.clone());
+                Params::Array(params)
             }
-            EngineEvent::UserChanged => {
-                let name = engine.get_current_user()?;
-                let event = EngineNotification::UserChanged { name };
+            NotificationFormat::Named => {
+                let meta = Meta {
+                    seq,
+                    time: self.time,
+                    pause_time: self.pause_time,
+                };
+                let meta = match serde_json::to_value(&meta)? {
+                    Value::Object(meta) => meta,
+                    _ => unreachable!("meta should serialize to an object"),
+                };
 
-                Ok(event)
+                let mut params = Map::new();
+                if let Some(id) = self.grammar_id {
+                    params.insert("grammar_id".to_owned(), Value::from(id));
+                }
+                params.insert(self.event_name.to_owned(), self.event.clone());
+                params.extend(meta);
+                Params::Map(params)
             }
-        }
+        };
+
+        let n = Notification {
+            jsonrpc: Some(Version::V2),
+            method: self.method.clone(),
+            params,
+        };
+
+        Ok(serde_json::to_string(&n)?)
     }
 }
 
-pub fn create_notification<E>(id: u64, method: &str, event: &E) -> Result<String>
+/// Creates a notification about grammar `id`, unless `filter` says the
+/// client is not interested in events of `kind`. The event is only
+/// serialized if it passes.
+pub fn create_notification<E>(
+    filter: &Filter,
+    id: u64,
+    method: &str,
+    kind: Option<EventKind>,
+    event: &E,
+    pause_time: Option<u64>,
+) -> Option<Result<Outgoing>>
 where
     E: Serialize,
 {
-    let v_event = serde_json::to_value(event)?;
-    let v_id = serde_json::to_value(&id)?;
-    let p = Params::Array(vec![v_id, v_event]);
-    let n = Notification {
-        jsonrpc: Some(Version::V2),
-        method: method.to_owned(),
-        params: p,
-    };
+    if !filter.allows(id, kind) {
+        return None;
+    }
 
-    Ok(serde_json::to_string(&n)?)
+    let result = serde_json::to_value(event).map(|event| Outgoing {
+        pause_time,
+        ..Outgoing::new(method, Some(id), event)
+    });
+
+    Some(result.map_err(MyError::from))
+}
+
+fn create_server_notification(method: &str, event_name: &'static str, event: Value) -> Outgoing {
+    Outgoing {
+        event_name,
+        ..Outgoing::new(method, None, event)
+    }
+}
+
+/// Tells the client that `count` notifications were discarded because it
+/// did not keep up with them.
+pub fn create_dropped_notification(count: u64) -> Outgoing {
+    create_server_notification("notifications_dropped", "count", Value::from(count))
+}
+
+/// Tells the client that the connection to the engine changed.
+pub fn create_status_notification(status: EngineStatus) -> Result<Outgoing> {
+    let status = serde_json::to_value(&status)?;
+    Ok(create_server_notification(
+        "engine_status_changed",
+        "status",
+        status,
+    ))
+}
+
+/// Tells an authenticated client about an engine event, unless it
+/// registered for engine events itself.
+pub fn create_engine_event_notification(event: &EngineNotification) -> Result<Outgoing> {
+    let event = serde_json::to_value(event)?;
+    Ok(create_server_notification("engine_event", "event", event))
+}
+
+/// Tells the client that the engine came back after a restart, and that
+/// its grammars were loaded again. Those that could not be were reported
+/// with `grammar_reload_failed` before this. `generation` counts how often
+/// the server connected to the engine.
+pub fn create_recovered_notification(generation: u64) -> Outgoing {
+    let summary =
+        serde_json::to_value(RecoveredSummary { generation }).expect("summary should serialize");
+    create_server_notification("engine_recovered", "summary", summary)
+}
+
+/// Tells the client that the server is about to close the connection.
+pub fn create_shutdown_notification(reason: &str) -> Outgoing {
+    let details = ShutdownDetails {
+        reason: reason.to_owned(),
+    };
+    let details = serde_json::to_value(details).expect("details should serialize");
+    create_server_notification("server_shutting_down", "details", details)
 }
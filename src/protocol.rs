@@ -0,0 +1,291 @@
+use crate::errors::*;
+use crate::notifications::NotificationFormat;
+use crate::queue::NotificationSender;
+use crate::rpc::RpcProtocol;
+use jsonrpc_core::futures::future::{self, Either};
+use jsonrpc_core::middleware::Middleware;
+use jsonrpc_core::{Call, Error as RpcError, FutureOutput, FutureResponse, Output};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+/// The protocol versions the server speaks, oldest first. Connections use
+/// version 1 until they ask for another one.
+pub const PROTOCOL_VERSIONS: [u64; 2] = [1, 2];
+
+/// How the methods of a protocol version differ from the ones declared in
+/// rpc.rs. Every version has `hello`, since that is how a client switches.
+pub struct MethodChanges {
+    /// `(name in the version, name in rpc.rs)` for the methods the version
+    /// calls differently.
+    pub renamed: &'static [(&'static str, &'static str)],
+    /// The methods of rpc.rs the version does not have.
+    pub removed: &'static [&'static str],
+}
+
+const NO_CHANGES: MethodChanges = MethodChanges {
+    renamed: &[],
+    removed: &[],
+};
+
+/// The method changes of each protocol version, in the order of
+/// `PROTOCOL_VERSIONS`.
+static METHOD_CHANGES: [MethodChanges; 2] = [NO_CHANGES, NO_CHANGES];
+
+impl MethodChanges {
+    /// The changes of `version`, which has to be one the server speaks.
+    pub fn of(version: u64) -> &'static Self {
+        let i = PROTOCOL_VERSIONS
+            .iter()
+            .position(|&v| v == version)
+            .expect("protocol version should be supported");
+        &METHOD_CHANGES[i]
+    }
+
+    /// The method of rpc.rs that a client calling `method` means, if the
+    /// version has such a method.
+    pub fn resolve<'a>(&self, method: &'a str) -> Option<&'a str> {
+        if let Some(&(_, name)) = self.renamed.iter().find(|&&(called, _)| called == method) {
+            return Some(name);
+        }
+
+        // a method that was renamed is gone under its old name
+        let renamed = self.renamed.iter().any(|&(_, name)| name == method);
+        if renamed || self.removed.contains(&method) {
+            return None;
+        }
+
+        Some(method)
+    }
+
+    /// What the version calls the method of rpc.rs named `name`, if it has
+    /// it.
+    pub fn name_of<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if self.removed.contains(&name) {
+            return None;
+        }
+
+        match self.renamed.iter().find(|&&(_, n)| n == name) {
+            Some(&(called, _)) => Some(called),
+            None => Some(name),
+        }
+    }
+}
+
+/// How notifications are laid out in a protocol version. Version 1 sends
+/// positional params, later versions named ones.
+pub fn notification_format(version: u64) -> NotificationFormat {
+    match version {
+        1 => NotificationFormat::Positional,
+        _ => NotificationFormat::Named,
+    }
+}
+
+/// A way of connecting to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Websocket,
+}
+
+/// Something a client can rely on the server to support. Some depend on
+/// how the server was configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Clients have to call `auth` first.
+    Auth,
+    Mimic,
+    /// Sessions can be resumed after the connection drops.
+    Sessions,
+    Filters,
+    SharedGrammars,
+    /// `engine_register` can hold pauses until `engine_resume`.
+    PauseHolding,
+    /// Clients may shut the server down.
+    Shutdown,
+    Discover,
+}
+
+/// What `hello` tells a client about the server.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ServerInfo {
+    pub server_version: String,
+    pub protocol_versions: Vec<u64>,
+    /// The version the connection uses from now on.
+    pub protocol_version: u64,
+    pub transports: Vec<Transport>,
+    pub capabilities: Vec<Capability>,
+}
+
+struct VersionState {
+    version: u64,
+    methods: &'static MethodChanges,
+    chosen: bool,
+}
+
+/// The protocol version of a single session. A client can choose it once,
+/// and keeps it when it resumes the session on another connection. The
+/// notifications that are still queued when it does are laid out for the
+/// new version.
+pub struct Protocol {
+    notifications: NotificationSender,
+    state: Mutex<VersionState>,
+}
+
+impl Protocol {
+    pub fn new(notifications: NotificationSender) -> Arc<Self> {
+        Arc::new(Protocol {
+            notifications,
+            state: Mutex::new(VersionState {
+                version: PROTOCOL_VERSIONS[0],
+                methods: MethodChanges::of(PROTOCOL_VERSIONS[0]),
+                chosen: false,
+            }),
+        })
+    }
+
+    pub fn version(&self) -> u64 {
+        lock(&self.state).version
+    }
+
+    /// How the methods of the version differ from rpc.rs.
+    pub fn methods(&self) -> &'static MethodChanges {
+        lock(&self.state).methods
+    }
+
+    fn choose(&self, version: u64) -> Result<()> {
+        if !PROTOCOL_VERSIONS.contains(&version) {
+            return Err(ErrorKind::UnsupportedProtocolVersion {
+                version,
+                supported: PROTOCOL_VERSIONS.to_vec(),
+            }
+            .into());
+        }
+
+        let mut state = lock(&self.state);
+        if version == state.version {
+            return Ok(());
+        }
+
+        if state.chosen {
+            return Err(ErrorKind::ProtocolVersionFixed {
+                version: state.version,
+            }
+            .into());
+        }
+
+        self.notifications.set_format(notification_format(version));
+        state.version = version;
+        state.methods = MethodChanges::of(version);
+        state.chosen = true;
+        Ok(())
+    }
+}
+
+pub struct RpcProtocolImpl {
+    protocol: Arc<Protocol>,
+    transports: Vec<Transport>,
+    capabilities: Vec<Capability>,
+}
+
+impl RpcProtocolImpl {
+    pub fn new(
+        protocol: Arc<Protocol>,
+        transports: Vec<Transport>,
+        capabilities: Vec<Capability>,
+    ) -> Self {
+        RpcProtocolImpl {
+            protocol,
+            transports,
+            capabilities,
+        }
+    }
+}
+
+impl RpcProtocol for RpcProtocolImpl {
+    fn hello(&self, protocol_version: Option<u64>) -> Result<ServerInfo> {
+        if let Some(version) = protocol_version {
+            self.protocol_version(version)?;
+        }
+
+        Ok(ServerInfo {
+            server_version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+            protocol_version: self.protocol.version(),
+            transports: self.transports.clone(),
+            capabilities: self.capabilities.clone(),
+        })
+    }
+
+    fn protocol_version(&self, version: u64) -> Result<()> {
+        self.protocol.choose(version)
+    }
+}
+
+/// Dispatches calls by the method names of the session's protocol version.
+/// Methods the version does not have are not found, even if rpc.rs has
+/// them.
+pub struct ProtocolMiddleware(pub Arc<Protocol>);
+
+impl Middleware<()> for ProtocolMiddleware {
+    type Future = FutureResponse;
+    type CallFuture = FutureOutput;
+
+    fn on_call<F, X>(&self, call: Call, meta: (), next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, ()) -> X + Send + Sync,
+        X: future::Future<Item = Option<Output>, Error = ()> + Send + 'static,
+    {
+        let methods = self.0.methods();
+
+        match call {
+            Call::MethodCall(mut c) => match methods.resolve(&c.method).map(str::to_owned) {
+                Some(method) => {
+                    c.method = method;
+                    Either::B(next(Call::MethodCall(c), meta))
+                }
+                None => {
+                    let output = Output::from(Err(RpcError::method_not_found()), c.id, c.jsonrpc);
+                    Either::A(Box::new(future::ok(Some(output))))
+                }
+            },
+            Call::Notification(mut n) => match methods.resolve(&n.method).map(str::to_owned) {
+                Some(method) => {
+                    n.method = method;
+                    Either::B(next(Call::Notification(n), meta))
+                }
+                None => Either::A(Box::new(future::ok(None))),
+            },
+            call => Either::B(next(call, meta)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANGES: MethodChanges = MethodChanges {
+        renamed: &[("grammar_list", "list_grammars")],
+        removed: &["engine_mimic"],
+    };
+
+    #[test]
+    fn versions_can_rename_and_remove_methods() {
+        assert_eq!(CHANGES.resolve("grammar_list"), Some("list_grammars"));
+        assert_eq!(CHANGES.resolve("list_grammars"), None);
+        assert_eq!(CHANGES.resolve("engine_mimic"), None);
+        assert_eq!(CHANGES.resolve("hello"), Some("hello"));
+
+        assert_eq!(CHANGES.name_of("list_grammars"), Some("grammar_list"));
+        assert_eq!(CHANGES.name_of("engine_mimic"), None);
+        assert_eq!(CHANGES.name_of("hello"), Some("hello"));
+    }
+}
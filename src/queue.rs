@@ -0,0 +1,319 @@
+use crate::errors::*;
+use crate::notifications::{create_dropped_notification, NotificationFormat, Outgoing};
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+use log::warn;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What to do with a notification that arrives while the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "unknown overflow policy {}, expected drop-oldest, drop-newest or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+enum Entry {
+    Notification(Result<Outgoing>),
+    /// Stands in for this many notifications that were dropped. It takes
+    /// over the sequence number of the last of them.
+    Dropped(u64),
+}
+
+struct Inner {
+    config: QueueConfig,
+    items: VecDeque<(u64, Entry)>,
+    /// The number of notifications in `items`, not counting markers.
+    queued: usize,
+    next_seq: u64,
+    format: NotificationFormat,
+    overflowed: bool,
+    senders: usize,
+    receiver_alive: bool,
+    task: Option<Task>,
+}
+
+impl Inner {
+    /// Sequence numbers start at 1 and are handed out to every
+    /// notification, including the ones that end up being dropped, so the
+    /// client can tell where it missed something.
+    fn seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    /// Makes room by dropping the oldest notification. The markers for
+    /// notifications dropped before it absorb it.
+    fn drop_oldest(&mut self) {
+        let oldest = self.items.iter().position(|&(_, ref entry)| match *entry {
+            Entry::Notification(_) => true,
+            Entry::Dropped(_) => false,
+        });
+
+        let seq = match oldest.and_then(|i| self.items.remove(i)) {
+            Some((seq, _)) => seq,
+            None => return,
+        };
+        self.queued -= 1;
+
+        let mut count = 1;
+        while let Some(&(_, Entry::Dropped(earlier))) = self.items.front() {
+            self.items.pop_front();
+            count += earlier;
+        }
+
+        self.items.push_front((seq, Entry::Dropped(count)));
+    }
+
+    fn drop_newest(&mut self, seq: u64) {
+        let mut count = 1;
+
+        if let Some(&(_, Entry::Dropped(earlier))) = self.items.back() {
+            self.items.pop_back();
+            count += earlier;
+        }
+
+        self.items.push_back((seq, Entry::Dropped(count)));
+    }
+
+    fn wake(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+fn lock(inner: &Mutex<Inner>) -> MutexGuard<Inner> {
+    inner.lock().expect("attempt to lock poisoned mutex")
+}
+
+/// Creates a bounded queue for the notifications of a single connection.
+pub fn queue(config: QueueConfig) -> (NotificationSender, NotificationReceiver) {
+    let inner = Arc::new(Mutex::new(Inner {
+        config,
+        items: VecDeque::new(),
+        queued: 0,
+        next_seq: 0,
+        format: NotificationFormat::Positional,
+        overflowed: false,
+        senders: 1,
+        receiver_alive: true,
+        task: None,
+    }));
+
+    let sender = NotificationSender {
+        inner: inner.clone(),
+    };
+    let receiver = NotificationReceiver { inner };
+
+    (sender, receiver)
+}
+
+/// Sending half of a notification queue. Sending never blocks and never
+/// fails: notifications for a connection that has gone away are discarded.
+pub struct NotificationSender {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl NotificationSender {
+    /// Whether the receiving connection has gone away.
+    pub fn is_closed(&self) -> bool {
+        !lock(&self.inner).receiver_alive
+    }
+
+    /// Whether `other` sends to the same connection.
+    pub fn same_queue(&self, other: &NotificationSender) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Changes how notifications are laid out, including the ones that are
+    /// still queued, since they are only serialized once they are sent.
+    pub fn set_format(&self, format: NotificationFormat) {
+        lock(&self.inner).format = format;
+    }
+
+    pub fn send(&self, item: Result<Outgoing>) {
+        let mut inner = lock(&self.inner);
+
+        if !inner.receiver_alive || inner.overflowed {
+            return;
+        }
+
+        let seq = inner.seq();
+
+        if inner.queued >= inner.config.capacity {
+            match inner.config.policy {
+                OverflowPolicy::DropOldest => inner.drop_oldest(),
+                OverflowPolicy::DropNewest => {
+                    inner.drop_newest(seq);
+                    inner.wake();
+                    return;
+                }
+                OverflowPolicy::Disconnect => {
+                    warn!("notification queue overflowed, disconnecting client");
+                    inner.overflowed = true;
+                    inner.wake();
+                    return;
+                }
+            }
+        }
+
+        inner.items.push_back((seq, Entry::Notification(item)));
+        inner.queued += 1;
+        inner.wake();
+    }
+}
+
+impl Clone for NotificationSender {
+    fn clone(&self) -> Self {
+        lock(&self.inner).senders += 1;
+
+        NotificationSender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for NotificationSender {
+    fn drop(&mut self) {
+        let mut inner = lock(&self.inner);
+        inner.senders -= 1;
+
+        if inner.senders == 0 {
+            inner.wake();
+        }
+    }
+}
+
+/// Receiving half of a notification queue. The stream ends once every
+/// sender is gone, and fails if the client was disconnected because of an
+/// overflow.
+pub struct NotificationReceiver {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl NotificationReceiver {
+    /// Serializes `item` with the next sequence number, for notifications
+    /// that are sent right away instead of going through the queue.
+    pub fn render(&self, item: &Outgoing) -> Result<String> {
+        let mut inner = lock(&self.inner);
+        let seq = inner.seq();
+        item.render(seq, inner.format)
+    }
+}
+
+impl Stream for NotificationReceiver {
+    type Item = String;
+    type Error = MyError;
+
+    fn poll(&mut self) -> Poll<Option<String>, MyError> {
+        let mut inner = lock(&self.inner);
+
+        if inner.overflowed {
+            return Err(ErrorKind::QueueOverflow.into());
+        }
+
+        let format = inner.format;
+        match inner.items.pop_front() {
+            Some((seq, Entry::Notification(item))) => {
+                inner.queued -= 1;
+                Ok(Async::Ready(Some(item?.render(seq, format)?)))
+            }
+            Some((seq, Entry::Dropped(count))) => {
+                let notification = create_dropped_notification(count).render(seq, format)?;
+                Ok(Async::Ready(Some(notification)))
+            }
+            None if inner.senders == 0 => Ok(Async::Ready(None)),
+            None => {
+                inner.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Drop for NotificationReceiver {
+    fn drop(&mut self) {
+        let mut inner = lock(&self.inner);
+        inner.receiver_alive = false;
+        inner.items.clear();
+        inner.queued = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::create_recovered_notification;
+    use serde_json::Value;
+
+    fn next(receiver: &mut NotificationReceiver) -> Value {
+        match receiver.poll() {
+            Ok(Async::Ready(Some(message))) => serde_json::from_str(&message).unwrap(),
+            _ => panic!("expected a notification"),
+        }
+    }
+
+    #[test]
+    fn overflowing_twice_counts_both_drops() {
+        let config = QueueConfig {
+            capacity: 1,
+            policy: OverflowPolicy::DropOldest,
+        };
+        let (sender, mut receiver) = queue(config);
+
+        for generation in 1..4 {
+            sender.send(Ok(create_recovered_notification(generation)));
+        }
+
+        let dropped = next(&mut receiver);
+        assert_eq!(dropped["method"], "notifications_dropped");
+        assert_eq!(dropped["params"][0], 2);
+
+        let kept = next(&mut receiver);
+        assert_eq!(kept["method"], "engine_recovered");
+        assert_eq!(kept["params"][0]["generation"], 3);
+    }
+
+    #[test]
+    fn queued_notifications_take_the_new_format() {
+        let config = QueueConfig {
+            capacity: 4,
+            policy: OverflowPolicy::DropNewest,
+        };
+        let (sender, mut receiver) = queue(config);
+
+        sender.send(Ok(create_recovered_notification(1)));
+        sender.set_format(NotificationFormat::Named);
+
+        let recovered = next(&mut receiver);
+        assert_eq!(recovered["params"]["summary"]["generation"], 1);
+        assert_eq!(recovered["params"]["seq"], 1);
+    }
+}
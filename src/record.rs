@@ -0,0 +1,396 @@
+use crate::backend::*;
+use crate::errors::*;
+use crate::notifications::{timestamp, EngineNotification};
+use crate::rpcimpl::GrammarKind;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use stentorian::engine::{
+    CatchallGrammarEvent, CommandGrammarEvent, DictationGrammarEvent, GrammarEvent,
+    MicrophoneState, Recognition, SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+/// How a grammar event ended. Only these can be replayed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Recognized { words: Vec<String> },
+    Other,
+    Rejected,
+    Unsupported { event: Value },
+}
+
+impl Outcome {
+    fn from_event(e: &GrammarEvent<Vec<String>>) -> Self {
+        match e {
+            GrammarEvent::PhraseFinish(Recognition::Self_(words)) => Outcome::Recognized {
+                words: words.clone(),
+            },
+            GrammarEvent::PhraseFinish(Recognition::Other) => Outcome::Other,
+            GrammarEvent::PhraseFinish(Recognition::Reject) => Outcome::Rejected,
+            #[allow(unreachable_patterns)]
+            _ => Outcome::Unsupported {
+                event: serde_json::to_value(e).unwrap_or(Value::Null),
+            },
+        }
+    }
+
+    /// The event to feed to a grammar during a replay.
+    pub fn to_event(&self) -> Option<GrammarEvent<Vec<String>>> {
+        match *self {
+            Outcome::Recognized { ref words } => Some(GrammarEvent::PhraseFinish(
+                Recognition::Self_(words.clone()),
+            )),
+            Outcome::Other => Some(GrammarEvent::PhraseFinish(Recognition::Other)),
+            Outcome::Rejected => Some(GrammarEvent::PhraseFinish(Recognition::Reject)),
+            Outcome::Unsupported { .. } => None,
+        }
+    }
+}
+
+/// A single line of a recording. `time` is in milliseconds since the Unix
+/// epoch. Grammars are numbered in the order they were loaded, counting
+/// from 1 across all connections, which is also the order in which a
+/// replay loads them again. Grammars loaded by a client also carry the
+/// connection that opened its session and the id it was given there.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Request {
+        time: u64,
+        connection: u64,
+        message: String,
+    },
+    Disconnect {
+        time: u64,
+        connection: u64,
+    },
+    GrammarEvent {
+        time: u64,
+        kind: GrammarKind,
+        grammar: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        connection: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        grammar_id: Option<u64>,
+        #[serde(flatten)]
+        outcome: Outcome,
+    },
+    EngineEvent {
+        time: u64,
+        event: EngineNotification,
+    },
+}
+
+/// Tokens passed to `auth` and `session_resume` should not end up in files
+/// that get attached to bug reports, whether they are sent on their own or
+/// in a batch.
+fn redact(message: &str) -> String {
+    let mut value: Value = match serde_json::from_str(message) {
+        Ok(value) => value,
+        Err(_) => return message.to_owned(),
+    };
+
+    let redacted = match value {
+        Value::Array(ref mut calls) => calls
+            .iter_mut()
+            .fold(false, |redacted, call| redact_call(call) || redacted),
+        ref mut call => redact_call(call),
+    };
+
+    if redacted {
+        value.to_string()
+    } else {
+        message.to_owned()
+    }
+}
+
+fn redact_call(call: &mut Value) -> bool {
+    match call["method"].as_str() {
+        Some("auth") | Some("session_resume") => {
+            call["params"] = json!(["<redacted>"]);
+            true
+        }
+        _ => false,
+    }
+}
+
+struct Counters {
+    connections: u64,
+    grammars: u64,
+    /// The labels of the grammars loaded by clients, by their number.
+    labels: HashMap<u64, Label>,
+}
+
+/// Writes a recording, one JSON entry per line.
+pub struct Recorder {
+    file: Mutex<BufWriter<File>>,
+    counters: Mutex<Counters>,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        let file = File::create(path)?;
+
+        Ok(Arc::new(Recorder {
+            file: Mutex::new(BufWriter::new(file)),
+            counters: Mutex::new(Counters {
+                connections: 0,
+                grammars: 0,
+                labels: HashMap::new(),
+            }),
+        }))
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut file = lock(&self.file);
+
+        // flushed right away, since the recording matters most when the
+        // server does not exit cleanly
+        let result = serde_json::to_writer(&mut *file, entry)
+            .map_err(MyError::from)
+            .and_then(|()| Ok(writeln!(file)?))
+            .and_then(|()| Ok(file.flush()?));
+
+        if let Err(e) = result {
+            error!("could not write recording: {}", e.0);
+        }
+    }
+
+    pub fn new_connection(&self) -> u64 {
+        let mut counters = lock(&self.counters);
+        counters.connections += 1;
+        counters.connections
+    }
+
+    pub fn request(&self, connection: u64, message: &str) {
+        self.write(&Entry::Request {
+            time: timestamp(),
+            connection,
+            message: redact(message),
+        });
+    }
+
+    pub fn disconnect(&self, connection: u64) {
+        self.write(&Entry::Disconnect {
+            time: timestamp(),
+            connection,
+        });
+    }
+
+    fn grammar_event(&self, kind: GrammarKind, grammar: u64, event: &GrammarEvent<Vec<String>>) {
+        let label = lock(&self.counters).labels.get(&grammar).cloned();
+
+        self.write(&Entry::GrammarEvent {
+            time: timestamp(),
+            kind,
+            grammar,
+            connection: label.map(|label| label.connection),
+            grammar_id: label.map(|label| label.id),
+            outcome: Outcome::from_event(event),
+        });
+    }
+
+    fn engine_event(&self, event: EngineNotification) {
+        self.write(&Entry::EngineEvent {
+            time: timestamp(),
+            event,
+        });
+    }
+
+    /// Loads a grammar through `load`, numbering it if that succeeds.
+    fn load<C, F>(&self, label: Option<Label>, load: F) -> Result<C>
+    where
+        F: FnOnce(u64) -> Result<C>,
+    {
+        let mut counters = lock(&self.counters);
+        let control = load(counters.grammars + 1)?;
+        counters.grammars += 1;
+
+        if let Some(label) = label {
+            let grammar = counters.grammars;
+            counters.labels.insert(grammar, label);
+        }
+
+        Ok(control)
+    }
+}
+
+/// A backend that writes the events of every grammar and registration to
+/// a `Recorder` before passing them on.
+pub struct Recording<B> {
+    inner: Arc<B>,
+    recorder: Arc<Recorder>,
+}
+
+impl<B: Backend> Recording<B> {
+    pub fn new(inner: B, recorder: Arc<Recorder>) -> Self {
+        Recording {
+            inner: Arc::new(inner),
+            recorder,
+        }
+    }
+
+    fn wrap<F>(
+        &self,
+        kind: GrammarKind,
+        id: u64,
+        callback: F,
+    ) -> impl Fn(GrammarEvent<Vec<String>>) + Sync + Send + 'static
+    where
+        F: Fn(GrammarEvent<Vec<String>>) + Sync + Send + 'static,
+    {
+        let recorder = self.recorder.clone();
+
+        move |e| {
+            recorder.grammar_event(kind, id, &e);
+            callback(e);
+        }
+    }
+}
+
+/// The state an engine event refers to, looked up while it is current.
+fn describe_event<B: Backend>(
+    engine: &Weak<B>,
+    e: &EngineEvent<B::PauseCookie>,
+) -> Option<EngineNotification> {
+    let engine = engine.upgrade()?;
+
+    let notification = match e {
+        // the token is only handed out once the pause reaches the clients
+        EngineEvent::Paused(_) => EngineNotification::Paused {
+            token: String::new(),
+        },
+        EngineEvent::MicrophoneState => EngineNotification::MicrophoneStateChanged {
+            state: engine.microphone_get_state().ok()?,
+        },
+        EngineEvent::UserChanged => EngineNotification::UserChanged {
+            name: engine.get_current_user().ok()?,
+        },
+    };
+
+    Some(notification)
+}
+
+impl<B: Backend> Backend for Recording<B> {
+    type CommandControl = B::CommandControl;
+    type SelectControl = B::SelectControl;
+    type DictationControl = B::DictationControl;
+    type CatchallControl = B::CatchallControl;
+    type Registration = B::Registration;
+    type PauseCookie = B::PauseCookie;
+
+    fn command_grammar_load<F>(
+        &self,
+        grammar: &Grammar,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<B::CommandControl>
+    where
+        F: Fn(CommandGrammarEvent) + Sync + Send + 'static,
+    {
+        self.recorder.load(label, |id| {
+            let callback = self.wrap(GrammarKind::Command, id, callback);
+            self.inner.command_grammar_load(grammar, label, callback)
+        })
+    }
+
+    fn select_grammar_load<F>(
+        &self,
+        select_words: &[String],
+        through_words: &[String],
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<B::SelectControl>
+    where
+        F: Fn(SelectGrammarEvent) + Sync + Send + 'static,
+    {
+        self.recorder.load(label, |id| {
+            let callback = self.wrap(GrammarKind::Select, id, callback);
+            self.inner
+                .select_grammar_load(select_words, through_words, label, callback)
+        })
+    }
+
+    fn dictation_grammar_load<F>(
+        &self,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<B::DictationControl>
+    where
+        F: Fn(DictationGrammarEvent) + Sync + Send + 'static,
+    {
+        self.recorder.load(label, |id| {
+            let callback = self.wrap(GrammarKind::Dictation, id, callback);
+            self.inner.dictation_grammar_load(label, callback)
+        })
+    }
+
+    fn catchall_grammar_load<F>(
+        &self,
+        label: Option<Label>,
+        callback: F,
+    ) -> Result<B::CatchallControl>
+    where
+        F: Fn(CatchallGrammarEvent) + Sync + Send + 'static,
+    {
+        self.recorder.load(label, |id| {
+            let callback = self.wrap(GrammarKind::Catchall, id, callback);
+            self.inner.catchall_grammar_load(label, callback)
+        })
+    }
+
+    fn register<F>(&self, callback: F) -> Result<B::Registration>
+    where
+        F: Fn(EngineEvent<B::PauseCookie>) + Sync + Send + 'static,
+    {
+        let recorder = self.recorder.clone();
+        // a strong reference would keep the engine alive from inside itself
+        let engine = Arc::downgrade(&self.inner);
+
+        self.inner.register(move |e| {
+            if let Some(event) = describe_event(&engine, &e) {
+                recorder.engine_event(event);
+            }
+            callback(e);
+        })
+    }
+
+    fn resume(&self, cookie: B::PauseCookie) -> Result<()> {
+        self.inner.resume(cookie)
+    }
+
+    fn microphone_set_state(&self, state: MicrophoneState) -> Result<()> {
+        self.inner.microphone_set_state(state)
+    }
+
+    fn microphone_get_state(&self) -> Result<MicrophoneState> {
+        self.inner.microphone_get_state()
+    }
+
+    fn get_current_user(&self) -> Result<Option<String>> {
+        self.inner.get_current_user()
+    }
+
+    fn mimic(&self, words: &[String]) -> Result<bool> {
+        self.inner.mimic(words)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.inner.ping()
+    }
+
+    fn prepare_thread() -> Result<()> {
+        B::prepare_thread()
+    }
+}
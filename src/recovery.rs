@@ -0,0 +1,412 @@
+use crate::backend::*;
+use crate::errors::*;
+use crate::validate;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use stentorian::engine::{
+    CatchallGrammarEvent, CommandGrammarEvent, DictationGrammarEvent, SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+
+type Callback<E> = Arc<dyn Fn(E) + Sync + Send>;
+
+/// Everything needed to load a grammar or registration again on a fresh
+/// engine, in the state the client last left it in.
+pub trait Restore<B: Backend>: Send + 'static {
+    type Control: Send + 'static;
+
+    fn load(&self, engine: &B) -> Result<Self::Control>;
+}
+
+struct Slot<C, S> {
+    control: Option<C>,
+    generation: u64,
+    state: S,
+    /// Whether loading it on the current engine failed.
+    lost: bool,
+    on_lost: Option<LostHook>,
+}
+
+/// A loaded grammar whose state is recorded, so it can be reloaded when
+/// the engine restarts.
+pub struct SlotCell<B: Backend, S: Restore<B>>(Mutex<Slot<S::Control, S>>);
+
+/// Implemented by every `SlotCell`, so the connector can keep them in one
+/// list.
+pub trait Recover<B>: Send + Sync {
+    /// Loads the grammar on `engine`, unless it already was. If that fails,
+    /// the grammar is lost until the next restart, and whoever loaded it is
+    /// told.
+    fn reload(&self, engine: &B, generation: u64) -> Result<()>;
+
+    /// Forgets the control of an engine that went away.
+    fn detach(&self);
+}
+
+impl<B: Backend, S: Restore<B>> SlotCell<B, S> {
+    fn slot(&self) -> MutexGuard<Slot<S::Control, S>> {
+        self.0.lock().expect("attempt to lock poisoned mutex")
+    }
+}
+
+impl<B: Backend, S: Restore<B>> Recover<B> for SlotCell<B, S> {
+    fn reload(&self, engine: &B, generation: u64) -> Result<()> {
+        let mut slot = self.slot();
+        if slot.generation == generation {
+            return Ok(());
+        }
+
+        slot.control = None;
+        match slot.state.load(engine) {
+            Ok(control) => {
+                slot.control = Some(control);
+                slot.generation = generation;
+                slot.lost = false;
+                Ok(())
+            }
+            Err(e) => {
+                slot.lost = true;
+                if let Some(ref f) = slot.on_lost {
+                    f(&e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn detach(&self) {
+        self.slot().control = None;
+    }
+}
+
+/// The control handed out by the connector. Operations are applied to the
+/// engine's control and then recorded.
+pub struct Recoverable<B: Backend, S: Restore<B>>(Arc<SlotCell<B, S>>);
+
+impl<B: Backend, S: Restore<B>> Recoverable<B, S> {
+    /// Loads `state` on `engine`.
+    pub fn load(engine: &B, generation: u64, state: S) -> Result<Self> {
+        let control = state.load(engine)?;
+        let slot = Slot {
+            control: Some(control),
+            generation,
+            state,
+            lost: false,
+            on_lost: None,
+        };
+
+        Ok(Recoverable(Arc::new(SlotCell(Mutex::new(slot)))))
+    }
+
+    pub fn cell(&self) -> Arc<SlotCell<B, S>> {
+        self.0.clone()
+    }
+
+    fn set_lost_hook(&self, f: LostHook) {
+        self.0.slot().on_lost = Some(f);
+    }
+
+    fn with<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&S::Control, &mut S) -> Result<R>,
+    {
+        let mut slot = self.0.slot();
+        let slot = &mut *slot;
+
+        match slot.control {
+            Some(ref control) => f(control, &mut slot.state),
+            None if slot.lost => Err(ErrorKind::GrammarLost.into()),
+            None => Err(ErrorKind::EngineNotConnected.into()),
+        }
+    }
+}
+
+pub struct CommandState {
+    pub grammar: Grammar,
+    pub label: Option<Label>,
+    pub callback: Callback<CommandGrammarEvent>,
+    pub active_rules: BTreeSet<String>,
+    pub lists: BTreeMap<String, Vec<String>>,
+}
+
+impl<B: Backend> Restore<B> for CommandState {
+    type Control = B::CommandControl;
+
+    fn load(&self, engine: &B) -> Result<B::CommandControl> {
+        let callback = self.callback.clone();
+        let control =
+            engine.command_grammar_load(&self.grammar, self.label, move |e| callback(e))?;
+
+        for (name, words) in &self.lists {
+            for word in words {
+                control.list_append(name, word)?;
+            }
+        }
+
+        for rule in &self.active_rules {
+            control.rule_activate(rule)?;
+        }
+
+        Ok(control)
+    }
+}
+
+impl<B: Backend> CommandControl for Recoverable<B, CommandState> {
+    fn rule_activate(&self, name: &str) -> Result<()> {
+        self.with(|control, state| {
+            validate::rule(&state.grammar, name)?;
+            control.rule_activate(name)?;
+            state.active_rules.insert(name.to_owned());
+            Ok(())
+        })
+    }
+
+    fn rule_deactivate(&self, name: &str) -> Result<()> {
+        self.with(|control, state| {
+            validate::rule(&state.grammar, name)?;
+            control.rule_deactivate(name)?;
+            state.active_rules.remove(name);
+            Ok(())
+        })
+    }
+
+    fn list_append(&self, name: &str, word: &str) -> Result<()> {
+        self.with(|control, state| {
+            validate::list(&state.grammar, name)?;
+            control.list_append(name, word)?;
+            state
+                .lists
+                .entry(name.to_owned())
+                .or_insert_with(Vec::new)
+                .push(word.to_owned());
+            Ok(())
+        })
+    }
+
+    fn list_remove(&self, name: &str, word: &str) -> Result<()> {
+        self.with(|control, state| {
+            validate::list(&state.grammar, name)?;
+            control.list_remove(name, word)?;
+            // the engine only removes one entry at a time
+            if let Some(words) = state.lists.get_mut(name) {
+                if let Some(i) = words.iter().position(|w| w == word) {
+                    words.remove(i);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn list_clear(&self, name: &str) -> Result<()> {
+        self.with(|control, state| {
+            validate::list(&state.grammar, name)?;
+            control.list_clear(name)?;
+            state.lists.remove(name);
+            Ok(())
+        })
+    }
+
+    fn when_lost(&self, f: LostHook) {
+        self.set_lost_hook(f);
+    }
+}
+
+pub struct SelectState {
+    pub select_words: Vec<String>,
+    pub through_words: Vec<String>,
+    pub label: Option<Label>,
+    pub callback: Callback<SelectGrammarEvent>,
+    pub active: bool,
+    pub text: String,
+}
+
+impl<B: Backend> Restore<B> for SelectState {
+    type Control = B::SelectControl;
+
+    fn load(&self, engine: &B) -> Result<B::SelectControl> {
+        let callback = self.callback.clone();
+        let control = engine.select_grammar_load(
+            &self.select_words,
+            &self.through_words,
+            self.label,
+            move |e| callback(e),
+        )?;
+
+        control.text_set(&self.text)?;
+        if self.active {
+            control.activate()?;
+        }
+
+        Ok(control)
+    }
+}
+
+impl<B: Backend> Recoverable<B, SelectState> {
+    /// Applies an edit, and remembers the resulting text as the engine
+    /// reports it.
+    fn edit<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&B::SelectControl) -> Result<()>,
+    {
+        self.with(|control, state| {
+            f(control)?;
+            if let Ok(text) = control.text_get() {
+                state.text = text;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<B: Backend> SelectControl for Recoverable<B, SelectState> {
+    fn activate(&self) -> Result<()> {
+        self.with(|control, state| {
+            control.activate()?;
+            state.active = true;
+            Ok(())
+        })
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        self.with(|control, state| {
+            control.deactivate()?;
+            state.active = false;
+            Ok(())
+        })
+    }
+
+    fn text_set(&self, text: &str) -> Result<()> {
+        self.edit(|control| control.text_set(text))
+    }
+
+    fn text_change(&self, start: u32, stop: u32, text: &str) -> Result<()> {
+        self.edit(|control| control.text_change(start, stop, text))
+    }
+
+    fn text_delete(&self, start: u32, stop: u32) -> Result<()> {
+        self.edit(|control| control.text_delete(start, stop))
+    }
+
+    fn text_insert(&self, start: u32, text: &str) -> Result<()> {
+        self.edit(|control| control.text_insert(start, text))
+    }
+
+    fn text_get(&self) -> Result<String> {
+        self.with(|control, _| control.text_get())
+    }
+
+    fn when_lost(&self, f: LostHook) {
+        self.set_lost_hook(f);
+    }
+}
+
+pub struct DictationState {
+    pub label: Option<Label>,
+    pub callback: Callback<DictationGrammarEvent>,
+    pub active: bool,
+    pub context: Option<String>,
+}
+
+impl<B: Backend> Restore<B> for DictationState {
+    type Control = B::DictationControl;
+
+    fn load(&self, engine: &B) -> Result<B::DictationControl> {
+        let callback = self.callback.clone();
+        let control = engine.dictation_grammar_load(self.label, move |e| callback(e))?;
+
+        if let Some(ref context) = self.context {
+            control.context_set(context)?;
+        }
+        if self.active {
+            control.activate()?;
+        }
+
+        Ok(control)
+    }
+}
+
+impl<B: Backend> DictationControl for Recoverable<B, DictationState> {
+    fn activate(&self) -> Result<()> {
+        self.with(|control, state| {
+            control.activate()?;
+            state.active = true;
+            Ok(())
+        })
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        self.with(|control, state| {
+            control.deactivate()?;
+            state.active = false;
+            Ok(())
+        })
+    }
+
+    fn context_set(&self, context: &str) -> Result<()> {
+        self.with(|control, state| {
+            control.context_set(context)?;
+            state.context = Some(context.to_owned());
+            Ok(())
+        })
+    }
+
+    fn when_lost(&self, f: LostHook) {
+        self.set_lost_hook(f);
+    }
+}
+
+pub struct CatchallState {
+    pub label: Option<Label>,
+    pub callback: Callback<CatchallGrammarEvent>,
+    pub active: bool,
+}
+
+impl<B: Backend> Restore<B> for CatchallState {
+    type Control = B::CatchallControl;
+
+    fn load(&self, engine: &B) -> Result<B::CatchallControl> {
+        let callback = self.callback.clone();
+        let control = engine.catchall_grammar_load(self.label, move |e| callback(e))?;
+
+        if self.active {
+            control.activate()?;
+        }
+
+        Ok(control)
+    }
+}
+
+impl<B: Backend> CatchallControl for Recoverable<B, CatchallState> {
+    fn activate(&self) -> Result<()> {
+        self.with(|control, state| {
+            control.activate()?;
+            state.active = true;
+            Ok(())
+        })
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        self.with(|control, state| {
+            control.deactivate()?;
+            state.active = false;
+            Ok(())
+        })
+    }
+
+    fn when_lost(&self, f: LostHook) {
+        self.set_lost_hook(f);
+    }
+}
+
+pub struct RegistrationState<B: Backend> {
+    pub callback: Callback<EngineEvent<B::PauseCookie>>,
+}
+
+impl<B: Backend> Restore<B> for RegistrationState<B> {
+    type Control = B::Registration;
+
+    fn load(&self, engine: &B) -> Result<B::Registration> {
+        let callback = self.callback.clone();
+        engine.register(move |e| callback(e))
+    }
+}
@@ -0,0 +1,171 @@
+use crate::auth::Authenticator;
+use crate::backend::Backend;
+use crate::config::{Config, PreloadConfig};
+use crate::connection::Shared;
+use crate::connector::Connector;
+use crate::errors::*;
+use crate::events::EngineEvents;
+use crate::notifications::EngineNotification;
+use crate::queue::QueueConfig;
+use crate::record::Entry;
+use crate::session::{Attachment, Session, SessionRegistry};
+use crate::shared::SharedGrammars;
+use crate::shutdown::Shutdown;
+use crate::simulated::SimulatedEngine;
+use futures::{future, Async, Future, Stream};
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use stentorian::grammar::Grammar;
+use tokio_core::reactor::Core;
+
+fn read_entries(path: &Path) -> Result<Vec<Entry>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for line in file.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Mimicked words already show up in the recording as the grammar events
+/// they caused, so performing them again would deliver everything twice.
+fn is_mimic(message: &str) -> bool {
+    match serde_json::from_str::<Value>(message) {
+        Ok(value) => value["method"] == "engine_mimic",
+        Err(_) => false,
+    }
+}
+
+/// Everything the connection has been sent so far.
+fn drain(core: &mut Core, attachment: &mut Attachment) -> Result<Vec<String>> {
+    core.run(future::poll_fn(|| {
+        let mut items = Vec::new();
+
+        while let Async::Ready(Some(item)) = attachment.poll()? {
+            items.push(item);
+        }
+
+        Ok(Async::Ready(items))
+    }))
+}
+
+fn replay_engine_event(engine: &SimulatedEngine, event: EngineNotification) -> Result<()> {
+    match event {
+        EngineNotification::Paused { .. } => engine.pause(),
+        EngineNotification::MicrophoneStateChanged { state } => {
+            engine.microphone_set_state(state)?
+        }
+        EngineNotification::UserChanged { name } => engine.user_set(name),
+    }
+
+    Ok(())
+}
+
+/// Runs the recording at `path` against the simulated engine, printing
+/// every response and notification to standard output, prefixed with the
+/// number of the connection it was sent to.
+///
+/// The grammars from the configuration file are loaded first, as they were
+/// when the recording was made. Grammar events are matched up with
+/// grammars by the order in which they were loaded, so a recording that
+/// spans an engine restart does not replay faithfully. Sessions cannot be
+/// resumed, since their tokens were left out of the recording.
+pub fn replay(config: &Config, grammars: &[(Grammar, PreloadConfig)], path: &Path) -> Result<()> {
+    let entries = read_entries(path)?;
+    let mut core = Core::new()?;
+
+    let simulated = SimulatedEngine::new();
+    let engine = Connector::new();
+    engine.attach(simulated.clone());
+
+    let shared = Shared {
+        events: EngineEvents::new(
+            engine.clone(),
+            Duration::from_millis(config.limits.pause_timeout_ms),
+        ),
+        grammars: SharedGrammars::new(engine.clone()),
+        engine,
+        // the tokens were left out of the recording
+        authenticator: Arc::new(Authenticator::disabled()),
+        queue: QueueConfig {
+            capacity: config.limits.queue_size,
+            policy: config.limits.overflow_policy,
+        },
+        sessions: SessionRegistry::new(Duration::from_secs(0)),
+        shutdown: Shutdown::new(),
+        allow_shutdown: false,
+        recorder: None,
+        transports: Vec::new(),
+    };
+    shared.events.start()?;
+    let _preloaded = crate::preload(&shared.grammars, grammars)?;
+    let peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+    let mut connections: BTreeMap<u64, Attachment> = BTreeMap::new();
+
+    info!(
+        "replaying {} entries from {}",
+        entries.len(),
+        path.display()
+    );
+
+    for entry in entries {
+        match entry {
+            Entry::Request {
+                connection,
+                message,
+                ..
+            } => {
+                let attachment = connections
+                    .entry(connection)
+                    .or_insert_with(|| Attachment::new(Session::new(&shared, peer, None)));
+
+                if is_mimic(&message) {
+                    info!("[{}] skipping {}", connection, message);
+                    continue;
+                }
+
+                let response = core
+                    .run(attachment.handle_request(&message))
+                    .expect("handle_request should never fail");
+
+                if let Some(response) = response {
+                    println!("[{}] {}", connection, response);
+                }
+            }
+            Entry::Disconnect { connection, .. } => {
+                connections.remove(&connection);
+            }
+            Entry::GrammarEvent {
+                grammar, outcome, ..
+            } => match outcome.to_event() {
+                Some(event) => {
+                    if !simulated.inject(grammar, event) {
+                        warn!("grammar {} is not loaded, skipping its event", grammar);
+                    }
+                }
+                None => warn!("cannot replay {:?}, skipping it", outcome),
+            },
+            Entry::EngineEvent { event, .. } => replay_engine_event(&simulated, event)?,
+        }
+
+        for (connection, attachment) in connections.iter_mut() {
+            for notification in drain(&mut core, attachment)? {
+                println!("[{}] {}", connection, notification);
+            }
+        }
+    }
+
+    Ok(())
+}
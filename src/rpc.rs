@@ -1,111 +1,227 @@
+use crate::connector::EngineStatus;
+use crate::discover::{Document, Param};
 use crate::errors::MyError as Error;
+use crate::filter::EventKind;
+use crate::protocol::ServerInfo;
+use crate::rpcimpl::GrammarInfo;
 use jsonrpc_core;
 use jsonrpc_derive::rpc;
+use serde_json::Value;
 use stentorian::engine::MicrophoneState;
 use stentorian::grammar::Grammar;
 
-#[rpc(server)]
-pub trait RpcCommand {
-    #[rpc(name = "command_grammar_load")]
-    fn load(&self, grammar: Grammar) -> Result<u64, Error>;
+/// Declares the traits of the API along with `describe_methods`, which adds
+/// each of their methods to the `rpc.discover` document. That way the
+/// document lists exactly the methods, params and results the server has.
+macro_rules! rpc_api {
+    ($(
+        #[rpc(server)]
+        pub trait $name:ident { $($body:tt)* }
+    )*) => {
+        $(
+            #[rpc(server)]
+            pub trait $name { $($body)* }
+        )*
+
+        pub fn describe_methods(doc: &mut Document) {
+            $(describe_trait!(doc; $($body)*);)*
+        }
+    };
+}
 
-    #[rpc(name = "command_grammar_unload")]
-    fn unload(&self, grammar_id: u64) -> Result<(), Error>;
+macro_rules! describe_trait {
+    ($doc:ident; $(
+        #[rpc(name = $method:tt)]
+        fn $f:ident(&self $(, $param:ident: $ty:ty)*) -> Result<$result:ty, Error>;
+    )*) => {
+        $($doc.method::<$result>($method, vec![$(Param::of::<$ty>(stringify!($param))),*]);)*
+    };
+}
 
-    #[rpc(name = "command_grammar_rule_activate")]
-    fn rule_activate(&self, grammar_id: u64, rule_name: String) -> Result<(), Error>;
+rpc_api! {
+    #[rpc(server)]
+    pub trait RpcAuth {
+        #[rpc(name = "auth")]
+        fn auth(&self, token: String) -> Result<(), Error>;
+    }
 
-    #[rpc(name = "command_grammar_rule_deactivate")]
-    fn rule_deactivate(&self, grammar_id: u64, rule_name: String) -> Result<(), Error>;
+    #[rpc(server)]
+    pub trait RpcSession {
+        #[rpc(name = "session_token")]
+        fn token(&self) -> Result<String, Error>;
 
-    #[rpc(name = "command_grammar_list_append")]
-    fn list_append(&self, grammar_id: u64, list_name: String, word: String) -> Result<(), Error>;
+        #[rpc(name = "session_resume")]
+        fn resume(&self, token: String) -> Result<(), Error>;
+    }
 
-    #[rpc(name = "command_grammar_list_remove")]
-    fn list_remove(&self, grammar_id: u64, list_name: String, word: String) -> Result<(), Error>;
+    #[rpc(server)]
+    pub trait RpcCommand {
+        #[rpc(name = "command_grammar_load")]
+        fn load(&self, grammar: Grammar, name: Option<String>) -> Result<u64, Error>;
 
-    #[rpc(name = "command_grammar_list_clear")]
-    fn list_clear(&self, grammar_id: u64, list_name: String) -> Result<(), Error>;
-}
+        #[rpc(name = "command_grammar_load_shared")]
+        fn load_shared(&self, grammar: Grammar, name: String) -> Result<u64, Error>;
 
-#[rpc(server)]
-pub trait RpcSelect {
-    #[rpc(name = "select_grammar_load")]
-    fn load(&self, select_words: Vec<String>, through_words: Vec<String>) -> Result<u64, Error>;
+        #[rpc(name = "command_grammar_attach")]
+        fn attach(&self, name: String) -> Result<u64, Error>;
 
-    #[rpc(name = "select_grammar_unload")]
-    fn unload(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "command_grammar_list_shared")]
+        fn list_shared(&self) -> Result<Vec<String>, Error>;
 
-    #[rpc(name = "select_grammar_activate")]
-    fn activate(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "command_grammar_unload")]
+        fn unload(&self, grammar_id: u64) -> Result<(), Error>;
 
-    #[rpc(name = "select_grammar_deactivate")]
-    fn deactivate(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "command_grammar_rule_activate")]
+        fn rule_activate(&self, grammar_id: u64, rule_name: String) -> Result<(), Error>;
 
-    #[rpc(name = "select_grammar_text_set")]
-    fn text_set(&self, grammar_id: u64, text: String) -> Result<(), Error>;
+        #[rpc(name = "command_grammar_rule_deactivate")]
+        fn rule_deactivate(&self, grammar_id: u64, rule_name: String) -> Result<(), Error>;
 
-    #[rpc(name = "select_grammar_text_change")]
-    fn text_change(&self, grammar_id: u64, start: u32, end: u32, text: String)
-        -> Result<(), Error>;
+        #[rpc(name = "command_grammar_list_append")]
+        fn list_append(&self, grammar_id: u64, list_name: String, word: String)
+            -> Result<(), Error>;
 
-    #[rpc(name = "select_grammar_text_delete")]
-    fn text_delete(&self, grammar_id: u64, start: u32, end: u32) -> Result<(), Error>;
+        #[rpc(name = "command_grammar_list_remove")]
+        fn list_remove(&self, grammar_id: u64, list_name: String, word: String)
+            -> Result<(), Error>;
 
-    #[rpc(name = "select_grammar_text_insert")]
-    fn text_insert(&self, grammar_id: u64, start: u32, text: String) -> Result<(), Error>;
+        #[rpc(name = "command_grammar_list_clear")]
+        fn list_clear(&self, grammar_id: u64, list_name: String) -> Result<(), Error>;
+    }
 
-    #[rpc(name = "select_grammar_text_get")]
-    fn text_get(&self, grammar_id: u64) -> Result<String, Error>;
-}
+    #[rpc(server)]
+    pub trait RpcSelect {
+        #[rpc(name = "select_grammar_load")]
+        fn load(&self, select_words: Vec<String>, through_words: Vec<String>) -> Result<u64, Error>;
 
-#[rpc(server)]
-pub trait RpcDictation {
-    #[rpc(name = "dictation_grammar_load")]
-    fn load(&self) -> Result<u64, Error>;
+        #[rpc(name = "select_grammar_unload")]
+        fn unload(&self, grammar_id: u64) -> Result<(), Error>;
 
-    #[rpc(name = "dictation_grammar_unload")]
-    fn unload(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "select_grammar_activate")]
+        fn activate(&self, grammar_id: u64) -> Result<(), Error>;
 
-    #[rpc(name = "dictation_grammar_activate")]
-    fn activate(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "select_grammar_deactivate")]
+        fn deactivate(&self, grammar_id: u64) -> Result<(), Error>;
 
-    #[rpc(name = "dictation_grammar_deactivate")]
-    fn deactivate(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "select_grammar_text_set")]
+        fn text_set(&self, grammar_id: u64, text: String) -> Result<(), Error>;
 
-    #[rpc(name = "dictation_grammar_context_set")]
-    fn context_set(&self, grammar_id: u64, context: String) -> Result<(), Error>;
-}
+        #[rpc(name = "select_grammar_text_change")]
+        fn text_change(&self, grammar_id: u64, start: u32, end: u32, text: String)
+            -> Result<(), Error>;
 
-#[rpc(server)]
-pub trait RpcCatchall {
-    #[rpc(name = "catchall_grammar_load")]
-    fn load(&self) -> Result<u64, Error>;
+        #[rpc(name = "select_grammar_text_delete")]
+        fn text_delete(&self, grammar_id: u64, start: u32, end: u32) -> Result<(), Error>;
 
-    #[rpc(name = "catchall_grammar_unload")]
-    fn unload(&self, rammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "select_grammar_text_insert")]
+        fn text_insert(&self, grammar_id: u64, start: u32, text: String) -> Result<(), Error>;
 
-    #[rpc(name = "catchall_grammar_activate")]
-    fn activate(&self, grammar_id: u64) -> Result<(), Error>;
+        #[rpc(name = "select_grammar_text_get")]
+        fn text_get(&self, grammar_id: u64) -> Result<String, Error>;
+    }
 
-    #[rpc(name = "catchall_grammar_deactivate")]
-    fn deactivate(&self, grammar_id: u64) -> Result<(), Error>;
-}
+    #[rpc(server)]
+    pub trait RpcDictation {
+        #[rpc(name = "dictation_grammar_load")]
+        fn load(&self) -> Result<u64, Error>;
+
+        #[rpc(name = "dictation_grammar_unload")]
+        fn unload(&self, grammar_id: u64) -> Result<(), Error>;
+
+        #[rpc(name = "dictation_grammar_activate")]
+        fn activate(&self, grammar_id: u64) -> Result<(), Error>;
+
+        #[rpc(name = "dictation_grammar_deactivate")]
+        fn deactivate(&self, grammar_id: u64) -> Result<(), Error>;
+
+        #[rpc(name = "dictation_grammar_context_set")]
+        fn context_set(&self, grammar_id: u64, context: String) -> Result<(), Error>;
+    }
+
+    #[rpc(server)]
+    pub trait RpcCatchall {
+        #[rpc(name = "catchall_grammar_load")]
+        fn load(&self) -> Result<u64, Error>;
+
+        #[rpc(name = "catchall_grammar_unload")]
+        fn unload(&self, grammar_id: u64) -> Result<(), Error>;
+
+        #[rpc(name = "catchall_grammar_activate")]
+        fn activate(&self, grammar_id: u64) -> Result<(), Error>;
+
+        #[rpc(name = "catchall_grammar_deactivate")]
+        fn deactivate(&self, grammar_id: u64) -> Result<(), Error>;
+    }
+
+    #[rpc(server)]
+    pub trait RpcEngine {
+        #[rpc(name = "engine_register")]
+        fn register(&self, hold_pauses: Option<bool>) -> Result<u64, Error>;
+
+        #[rpc(name = "engine_unregister")]
+        fn unregister(&self, grammar_id: u64) -> Result<(), Error>;
+
+        #[rpc(name = "microphone_set_state")]
+        fn microphone_set_state(&self, state: MicrophoneState) -> Result<(), Error>;
+
+        #[rpc(name = "microphone_get_state")]
+        fn microphone_get_state(&self) -> Result<MicrophoneState, Error>;
+
+        #[rpc(name = "get_current_user")]
+        fn get_current_user(&self) -> Result<Option<String>, Error>;
+
+        #[rpc(name = "engine_mimic")]
+        fn mimic(&self, words: Vec<String>) -> Result<bool, Error>;
+
+        #[rpc(name = "engine_resume")]
+        fn resume(&self, token: String) -> Result<(), Error>;
+    }
+
+    #[rpc(server)]
+    pub trait RpcAdmin {
+        #[rpc(name = "server_shutdown")]
+        fn shutdown(&self) -> Result<(), Error>;
+    }
+
+    #[rpc(server)]
+    pub trait RpcStatus {
+        #[rpc(name = "engine_status")]
+        fn engine_status(&self) -> Result<EngineStatus, Error>;
+    }
+
+    #[rpc(server)]
+    pub trait RpcFilter {
+        #[rpc(name = "subscribe")]
+        fn subscribe(&self, grammar_id: u64, kinds: Option<Vec<EventKind>>) -> Result<(), Error>;
+
+        #[rpc(name = "unsubscribe")]
+        fn unsubscribe(&self, grammar_id: u64, kinds: Option<Vec<EventKind>>) -> Result<(), Error>;
+    }
+
+    #[rpc(server)]
+    pub trait RpcProtocol {
+        #[rpc(name = "hello")]
+        fn hello(&self, protocol_version: Option<u64>) -> Result<ServerInfo, Error>;
 
-#[rpc(server)]
-pub trait RpcEngine {
-    #[rpc(name = "engine_register")]
-    fn register(&self) -> Result<u64, Error>;
+        #[rpc(name = "protocol_version")]
+        fn protocol_version(&self, version: u64) -> Result<(), Error>;
+    }
 
-    #[rpc(name = "engine_unregister")]
-    fn unregister(&self, grammar_id: u64) -> Result<(), Error>;
+    #[rpc(server)]
+    pub trait RpcIntrospect {
+        #[rpc(name = "list_grammars")]
+        fn list_grammars(&self) -> Result<Vec<GrammarInfo>, Error>;
 
-    #[rpc(name = "microphone_set_state")]
-    fn microphone_set_state(&self, state: MicrophoneState) -> Result<(), Error>;
+        #[rpc(name = "grammar_get")]
+        fn grammar_get(&self, grammar_id: u64) -> Result<Grammar, Error>;
 
-    #[rpc(name = "microphone_get_state")]
-    fn microphone_get_state(&self) -> Result<MicrophoneState, Error>;
+        #[rpc(name = "list_get")]
+        fn list_get(&self, grammar_id: u64, list_name: String) -> Result<Vec<String>, Error>;
+    }
 
-    #[rpc(name = "get_current_user")]
-    fn get_current_user(&self) -> Result<Option<String>, Error>;
+    #[rpc(server)]
+    pub trait RpcDiscover {
+        #[rpc(name = "rpc.discover")]
+        fn discover(&self) -> Result<Value, Error>;
+    }
 }
@@ -1,78 +1,295 @@
-use crate::errors::Result;
-use crate::notifications::{create_notification, EngineNotification};
+use crate::backend::*;
+use crate::errors::{ErrorKind, MyError, Result};
+use crate::events::{EngineEvents, Subscription};
+use crate::filter::{EventKind, Notifier};
 use crate::rpc::*;
-use futures::sync::mpsc;
-use std::collections::HashMap;
+use crate::shared::SharedGrammars;
+use crate::validate;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::sync::{Arc, Mutex, MutexGuard};
-use stentorian::engine::{
-    CatchallGrammarControl, CommandGrammarControl, CommandGrammarEvent, DictationGrammarControl,
-    Engine, EngineRegistration, MicrophoneState, SelectGrammarControl,
-};
+use stentorian::engine::{CommandGrammarEvent, GrammarEvent, MicrophoneState};
 use stentorian::grammar::Grammar;
 use stentorian::resultparser::Matcher;
 
-struct ConnectionState<T> {
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GrammarKind {
+    Command,
+    Select,
+    Dictation,
+    Catchall,
+    Engine,
+}
+
+impl fmt::Display for GrammarKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            GrammarKind::Command => "command grammar",
+            GrammarKind::Select => "select grammar",
+            GrammarKind::Dictation => "dictation grammar",
+            GrammarKind::Catchall => "catchall grammar",
+            GrammarKind::Engine => "engine registration",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// What the server itself knows about a loaded grammar, independently of
+/// the engine.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct GrammarInfo {
+    pub id: u64,
+    pub kind: GrammarKind,
+    pub name: Option<String>,
+    /// Whether other connections can attach to the grammar by its name.
+    pub shared: bool,
+    pub active: bool,
+    pub active_rules: BTreeSet<String>,
+    pub lists: BTreeMap<String, Vec<String>>,
+    #[serde(skip)]
+    pub grammar: Option<Grammar>,
+}
+
+impl GrammarInfo {
+    pub fn new(id: u64, kind: GrammarKind) -> Self {
+        GrammarInfo {
+            id,
+            kind,
+            name: None,
+            shared: false,
+            active: false,
+            active_rules: BTreeSet::new(),
+            lists: BTreeMap::new(),
+            grammar: None,
+        }
+    }
+}
+
+struct IdState {
     counter: u64,
+    grammars: HashMap<u64, Arc<Mutex<GrammarInfo>>>,
+}
+
+/// A copy of `info` as seen under `id`. The information about a shared
+/// grammar is shared along with it, but every connection knows the grammar
+/// by its own id.
+fn snapshot(id: u64, info: &Mutex<GrammarInfo>) -> GrammarInfo {
+    let mut info = lock(info).clone();
+    info.id = id;
+    info
+}
+
+/// Hands out the ids for a single connection and keeps track of what they
+/// refer to. All helpers of a connection share one id space, so an id of
+/// one kind can never be mistaken for an id of another kind.
+pub struct GrammarIds {
+    /// The number of the connection in the recording, if there is one.
+    connection: Option<u64>,
+    state: Mutex<IdState>,
+}
+
+impl GrammarIds {
+    pub fn new(connection: Option<u64>) -> Arc<Self> {
+        Arc::new(GrammarIds {
+            connection,
+            state: Mutex::new(IdState {
+                counter: 0,
+                grammars: HashMap::new(),
+            }),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<IdState> {
+        self.state.lock().expect("attempt to lock poisoned mutex")
+    }
+
+    fn allocate(&self) -> u64 {
+        let mut state = self.state();
+        state.counter += 1;
+        state.counter
+    }
+
+    /// How the grammar loaded under `id` shows up in the recording.
+    fn label(&self, id: u64) -> Option<Label> {
+        self.connection.map(|connection| Label { connection, id })
+    }
+
+    fn claim(&self, id: u64, kind: GrammarKind) {
+        let info = Arc::new(Mutex::new(GrammarInfo::new(id, kind)));
+        self.state().grammars.insert(id, info);
+    }
+
+    fn claim_shared(&self, id: u64, info: Arc<Mutex<GrammarInfo>>) {
+        self.state().grammars.insert(id, info);
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.state().grammars.contains_key(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state().grammars.is_empty()
+    }
+
+    fn release(&self, id: u64) {
+        self.state().grammars.remove(&id);
+    }
+
+    fn update<F: FnOnce(&mut GrammarInfo)>(&self, id: u64, f: F) {
+        if let Some(info) = self.state().grammars.get(&id) {
+            f(&mut lock(info));
+        }
+    }
+
+    /// The information about `id`, which has to be of kind `expected`.
+    pub fn info(&self, id: u64, expected: GrammarKind) -> Result<GrammarInfo> {
+        if let Some(info) = self.state().grammars.get(&id) {
+            let info = snapshot(id, info);
+            if info.kind == expected {
+                return Ok(info);
+            }
+        }
+
+        Err(self.missing(id, expected))
+    }
+
+    /// Information about every grammar, ordered by id.
+    pub fn all(&self) -> Vec<GrammarInfo> {
+        let mut grammars: Vec<GrammarInfo> = self
+            .state()
+            .grammars
+            .iter()
+            .map(|(&id, info)| snapshot(id, info))
+            .collect();
+        grammars.sort_by_key(|info| info.id);
+        grammars
+    }
+
+    /// The error to report when `id` is not a live id of kind `expected`.
+    fn missing(&self, id: u64, expected: GrammarKind) -> MyError {
+        match self.state().grammars.get(&id) {
+            Some(info) => ErrorKind::WrongGrammarKind {
+                id,
+                expected,
+                actual: lock(info).kind,
+            }
+            .into(),
+            None => ErrorKind::UnknownGrammar { id }.into(),
+        }
+    }
+}
+
+struct ConnectionState<T> {
+    kind: GrammarKind,
+    ids: Arc<GrammarIds>,
     items: HashMap<u64, T>,
 }
 
 impl<T> ConnectionState<T> {
-    fn new() -> Self {
+    fn new(kind: GrammarKind, ids: Arc<GrammarIds>) -> Self {
         ConnectionState {
-            counter: 0,
+            kind,
+            ids,
             items: HashMap::new(),
         }
     }
 
     fn new_id(&mut self) -> u64 {
-        self.counter += 1;
-        self.counter
+        self.ids.allocate()
+    }
+
+    fn label(&self, id: u64) -> Option<Label> {
+        self.ids.label(id)
     }
 
     fn insert(&mut self, id: u64, item: T) {
+        self.ids.claim(id, self.kind);
+        self.items.insert(id, item);
+    }
+
+    fn insert_shared(&mut self, id: u64, item: T, info: Arc<Mutex<GrammarInfo>>) {
+        self.ids.claim_shared(id, info);
         self.items.insert(id, item);
     }
 
     fn remove(&mut self, id: u64) -> Result<()> {
-        // TODO: error if it doesn't exist
-        self.items.remove(&id);
-        Ok(())
+        match self.items.remove(&id) {
+            Some(_) => {
+                self.ids.release(id);
+                Ok(())
+            }
+            None => Err(self.ids.missing(id, self.kind)),
+        }
+    }
+
+    fn update<F: FnOnce(&mut GrammarInfo)>(&self, id: u64, f: F) {
+        self.ids.update(id, f);
     }
 
     fn lookup(&self, id: u64) -> Result<&T> {
-        // TODO: error if it doesn't exist
-        Ok(&self.items[&id])
+        match self.items.get(&id) {
+            Some(item) => Ok(item),
+            None => Err(self.ids.missing(id, self.kind)),
+        }
     }
 }
 
-pub struct RpcHelper<T> {
-    engine: Arc<Engine>,
-    notifications: mpsc::UnboundedSender<Result<String>>,
+pub struct RpcHelper<B, T> {
+    engine: Arc<B>,
+    notifications: Notifier,
     state: Mutex<ConnectionState<T>>,
 }
 
-impl<T> RpcHelper<T> {
-    pub fn new(engine: Arc<Engine>, notifications: mpsc::UnboundedSender<Result<String>>) -> Self {
+impl<B, T> RpcHelper<B, T> {
+    pub fn new(
+        engine: Arc<B>,
+        notifications: Notifier,
+        kind: GrammarKind,
+        ids: Arc<GrammarIds>,
+    ) -> Self {
         RpcHelper {
             engine: engine,
             notifications: notifications,
-            state: Mutex::new(ConnectionState::new()),
+            state: Mutex::new(ConnectionState::new(kind, ids)),
         }
     }
 
     fn state(&self) -> MutexGuard<ConnectionState<T>> {
         self.state.lock().expect("attempt to lock poisoned mutex")
     }
-}
 
-pub struct RpcCommandImpl(pub RpcHelper<CommandGrammarControl>);
-pub struct RpcSelectImpl(pub RpcHelper<SelectGrammarControl>);
-pub struct RpcDictationImpl(pub RpcHelper<DictationGrammarControl>);
-pub struct RpcCatchallImpl(pub RpcHelper<CatchallGrammarControl>);
-pub struct RpcEngineImpl(pub RpcHelper<EngineRegistration>);
+    /// Drops `id`, along with what the client unsubscribed from for it.
+    fn unload(&self, id: u64) -> Result<()> {
+        self.state().remove(id)?;
+        self.notifications.forget(id);
+        Ok(())
+    }
+
+    fn lost_hook(&self, id: u64) -> LostHook {
+        let notifications = self.notifications.clone();
+        Box::new(move |e| notifications.grammar_lost(id, e))
+    }
+}
 
-impl RpcCommand for RpcCommandImpl {
-    fn load(&self, grammar: Grammar) -> Result<u64> {
+pub struct RpcCommandImpl<B: Backend>(
+    pub RpcHelper<B, Box<dyn CommandControl>>,
+    pub Arc<SharedGrammars<B>>,
+);
+pub struct RpcSelectImpl<B: Backend>(pub RpcHelper<B, B::SelectControl>);
+pub struct RpcDictationImpl<B: Backend>(pub RpcHelper<B, B::DictationControl>);
+pub struct RpcCatchallImpl<B: Backend>(pub RpcHelper<B, B::CatchallControl>);
+pub struct RpcEngineImpl<B: Backend>(pub RpcHelper<B, Subscription<B>>, pub Arc<EngineEvents<B>>);
+
+impl<B: Backend> RpcCommand for RpcCommandImpl<B> {
+    fn load(&self, grammar: Grammar, name: Option<String>) -> Result<u64> {
         let mut state = self.0.state();
         let id = state.new_id();
         let notifications = self.0.notifications.clone();
@@ -83,88 +300,149 @@ impl RpcCommand for RpcCommandImpl {
                 let matches = matcher.perform_match(&words);
                 (words, matches)
             });
-            let result = create_notification(id, "command_grammar_notification", &with_matches);
-            notifications.unbounded_send(result).unwrap();
+            let kind = EventKind::of(&with_matches);
+            notifications.notify(id, "command_grammar_notification", kind, &with_matches);
         };
 
-        let control = self.0.engine.command_grammar_load(&grammar, callback)?;
-        state.insert(id, control);
+        let control = self
+            .0
+            .engine
+            .command_grammar_load(&grammar, state.label(id), callback)?;
+        control.when_lost(self.0.lost_hook(id));
+        state.insert(id, Box::new(control));
+        state.update(id, |info| {
+            info.name = name;
+            info.grammar = Some(grammar);
+        });
 
         Ok(id)
     }
 
-    fn unload(&self, id: u64) -> Result<()> {
+    fn load_shared(&self, grammar: Grammar, name: String) -> Result<u64> {
         let mut state = self.0.state();
-        state.remove(id)?;
-        Ok(())
+        let id = state.new_id();
+        let notifications = self.0.notifications.clone();
+
+        let attached = self
+            .1
+            .load(&name, &grammar, id, state.label(id), notifications)?;
+        let info = attached.info();
+        state.insert_shared(id, Box::new(attached), info);
+
+        Ok(id)
+    }
+
+    fn attach(&self, name: String) -> Result<u64> {
+        let mut state = self.0.state();
+        let id = state.new_id();
+        let notifications = self.0.notifications.clone();
+
+        let attached = self.1.attach(&name, id, notifications)?;
+        let info = attached.info();
+        state.insert_shared(id, Box::new(attached), info);
+
+        Ok(id)
+    }
+
+    fn list_shared(&self) -> Result<Vec<String>> {
+        Ok(self.1.names())
+    }
+
+    fn unload(&self, id: u64) -> Result<()> {
+        self.0.unload(id)
     }
 
     fn rule_activate(&self, id: u64, name: String) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.rule_activate(&name)?;
+        state.update(id, |info| {
+            info.active_rules.insert(name);
+            info.active = true;
+        });
         Ok(())
     }
 
     fn rule_deactivate(&self, id: u64, name: String) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.rule_deactivate(&name)?;
+        state.update(id, |info| {
+            info.active_rules.remove(&name);
+            info.active = !info.active_rules.is_empty();
+        });
         Ok(())
     }
 
     fn list_append(&self, id: u64, name: String, word: String) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.list_append(&name, &word)?;
+        state.update(id, |info| {
+            info.lists.entry(name).or_insert_with(Vec::new).push(word);
+        });
         Ok(())
     }
 
     fn list_remove(&self, id: u64, name: String, word: String) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.list_remove(&name, &word)?;
+        state.update(id, |info| {
+            // the engine only removes one entry at a time
+            if let Some(words) = info.lists.get_mut(&name) {
+                if let Some(i) = words.iter().position(|w| *w == word) {
+                    words.remove(i);
+                }
+            }
+        });
         Ok(())
     }
 
     fn list_clear(&self, id: u64, name: String) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.list_clear(&name)?;
+        state.update(id, |info| {
+            info.lists.remove(&name);
+        });
         Ok(())
     }
 }
 
-impl RpcSelect for RpcSelectImpl {
+impl<B: Backend> RpcSelect for RpcSelectImpl<B> {
     fn load(&self, start_words: Vec<String>, through_words: Vec<String>) -> Result<u64> {
         let mut state = self.0.state();
         let id = state.new_id();
         let notifications = self.0.notifications.clone();
 
-        let callback = move |e| {
-            let result = create_notification(id, "select_grammar_notification", &e);
-            notifications.unbounded_send(result).unwrap();
+        let callback = move |e: GrammarEvent<_>| {
+            let kind = EventKind::of(&e);
+            notifications.notify(id, "select_grammar_notification", kind, &e);
         };
 
-        let control = self
-            .0
-            .engine
-            .select_grammar_load(&start_words, &through_words, callback)?;
+        let control = self.0.engine.select_grammar_load(
+            &start_words,
+            &through_words,
+            state.label(id),
+            callback,
+        )?;
+        control.when_lost(self.0.lost_hook(id));
         state.insert(id, control);
 
         Ok(id)
     }
 
     fn unload(&self, id: u64) -> Result<()> {
-        let mut state = self.0.state();
-        state.remove(id)?;
-        Ok(())
+        self.0.unload(id)
     }
 
     fn activate(&self, id: u64) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.activate()?;
+        state.update(id, |info| info.active = true);
         Ok(())
     }
 
     fn deactivate(&self, id: u64) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.deactivate()?;
+        state.update(id, |info| info.active = false);
         Ok(())
     }
 
@@ -199,38 +477,42 @@ impl RpcSelect for RpcSelectImpl {
     }
 }
 
-impl RpcDictation for RpcDictationImpl {
+impl<B: Backend> RpcDictation for RpcDictationImpl<B> {
     fn load(&self) -> Result<u64> {
         let mut state = self.0.state();
         let id = state.new_id();
         let notifications = self.0.notifications.clone();
 
-        let callback = move |e| {
-            let result = create_notification(id, "dictation_grammar_notification", &e);
-            notifications.unbounded_send(result).unwrap();
+        let callback = move |e: GrammarEvent<_>| {
+            let kind = EventKind::of(&e);
+            notifications.notify(id, "dictation_grammar_notification", kind, &e);
         };
 
-        let control = self.0.engine.dictation_grammar_load(callback)?;
+        let control = self
+            .0
+            .engine
+            .dictation_grammar_load(state.label(id), callback)?;
+        control.when_lost(self.0.lost_hook(id));
         state.insert(id, control);
 
         Ok(id)
     }
 
     fn unload(&self, id: u64) -> Result<()> {
-        let mut state = self.0.state();
-        state.remove(id)?;
-        Ok(())
+        self.0.unload(id)
     }
 
     fn activate(&self, id: u64) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.activate()?;
+        state.update(id, |info| info.active = true);
         Ok(())
     }
 
     fn deactivate(&self, id: u64) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.deactivate()?;
+        state.update(id, |info| info.active = false);
         Ok(())
     }
 
@@ -241,81 +523,110 @@ impl RpcDictation for RpcDictationImpl {
     }
 }
 
-impl RpcCatchall for RpcCatchallImpl {
+impl<B: Backend> RpcCatchall for RpcCatchallImpl<B> {
     fn load(&self) -> Result<u64> {
         let mut state = self.0.state();
         let id = state.new_id();
         let notifications = self.0.notifications.clone();
 
-        let callback = move |e| {
-            let result = create_notification(id, "catchall_grammar_notification", &e);
-            notifications.unbounded_send(result).unwrap();
+        let callback = move |e: GrammarEvent<_>| {
+            let kind = EventKind::of(&e);
+            notifications.notify(id, "catchall_grammar_notification", kind, &e);
         };
 
-        let control = self.0.engine.catchall_grammar_load(callback)?;
+        let control = self
+            .0
+            .engine
+            .catchall_grammar_load(state.label(id), callback)?;
+        control.when_lost(self.0.lost_hook(id));
         state.insert(id, control);
 
         Ok(id)
     }
 
     fn unload(&self, id: u64) -> Result<()> {
-        let mut state = self.0.state();
-        state.remove(id)?;
-        Ok(())
+        self.0.unload(id)
     }
 
     fn activate(&self, id: u64) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.activate()?;
+        state.update(id, |info| info.active = true);
         Ok(())
     }
 
     fn deactivate(&self, id: u64) -> Result<()> {
         let state = self.0.state();
         state.lookup(id)?.deactivate()?;
+        state.update(id, |info| info.active = false);
         Ok(())
     }
 }
 
-impl RpcEngine for RpcEngineImpl {
-    fn register(&self) -> Result<u64> {
+impl<B: Backend> RpcEngine for RpcEngineImpl<B> {
+    fn register(&self, hold_pauses: Option<bool>) -> Result<u64> {
         let mut state = self.0.state();
         let id = state.new_id();
 
-        let notifications = self.0.notifications.clone();
-        let engine = self.0.engine.clone();
-
-        let convert_event = move |e| {
-            let n = EngineNotification::from_event(&engine, e)?;
-            Ok(create_notification(id, "engine_notification", &n)?)
-        };
-
-        let callback = move |e| {
-            let result = convert_event(e);
-            notifications.unbounded_send(result).unwrap();
-        };
-
-        let registration = self.0.engine.register(callback)?;
-        state.insert(id, registration);
+        let hold_pauses = hold_pauses.unwrap_or(false);
+        let subscription = self
+            .1
+            .subscribe(id, hold_pauses, self.0.notifications.clone());
+        state.insert(id, subscription);
 
         Ok(id)
     }
 
     fn unregister(&self, id: u64) -> Result<()> {
-        let mut state = self.0.state();
-        state.remove(id)?;
-        Ok(())
+        self.0.unload(id)
     }
 
     fn microphone_set_state(&self, state: MicrophoneState) -> Result<()> {
-        Ok(self.0.engine.microphone_set_state(state)?)
+        self.0.engine.microphone_set_state(state)
     }
 
     fn microphone_get_state(&self) -> Result<MicrophoneState> {
-        Ok(self.0.engine.microphone_get_state()?)
+        self.0.engine.microphone_get_state()
     }
 
     fn get_current_user(&self) -> Result<Option<String>> {
-        Ok(self.0.engine.get_current_user()?)
+        self.0.engine.get_current_user()
+    }
+
+    fn mimic(&self, words: Vec<String>) -> Result<bool> {
+        self.0.engine.mimic(&words)
+    }
+
+    fn resume(&self, token: String) -> Result<()> {
+        let keys: Vec<u64> = self.0.state().items.values().map(|s| s.key()).collect();
+        self.1.resume(&keys, &token)
+    }
+}
+
+/// The definition of a command grammar, which is only missing while the
+/// grammar is still being loaded.
+fn definition(id: u64, info: &GrammarInfo) -> Result<&Grammar> {
+    match info.grammar {
+        Some(ref grammar) => Ok(grammar),
+        None => Err(ErrorKind::UnknownGrammar { id }.into()),
+    }
+}
+
+pub struct RpcIntrospectImpl(pub Arc<GrammarIds>);
+
+impl RpcIntrospect for RpcIntrospectImpl {
+    fn list_grammars(&self) -> Result<Vec<GrammarInfo>> {
+        Ok(self.0.all())
+    }
+
+    fn grammar_get(&self, id: u64) -> Result<Grammar> {
+        let info = self.0.info(id, GrammarKind::Command)?;
+        definition(id, &info).map(Clone::clone)
+    }
+
+    fn list_get(&self, id: u64, name: String) -> Result<Vec<String>> {
+        let info = self.0.info(id, GrammarKind::Command)?;
+        validate::list(definition(id, &info)?, &name)?;
+        Ok(info.lists.get(&name).cloned().unwrap_or_default())
     }
 }
@@ -0,0 +1,297 @@
+use crate::auth::AuthState;
+use crate::backend::Backend;
+use crate::connection::{create_handler, Handler, Shared};
+use crate::errors::*;
+use crate::notifications::Outgoing;
+use crate::protocol::Protocol;
+use crate::queue::{self, NotificationReceiver};
+use crate::rpc::RpcSession;
+use crate::rpcimpl::GrammarIds;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use log::{info, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+/// A random hex string that is hard to guess.
+pub fn new_token() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Everything a client has set up: its grammars, its pending notifications
+/// and its authentication state. A session normally lives exactly as long
+/// as its connection, but can outlive it for a grace period so the client
+/// can reconnect and pick up where it left off.
+pub struct Session {
+    token: String,
+    handler: Handler,
+    notifications: Mutex<NotificationReceiver>,
+    protocol: Arc<Protocol>,
+    auth: Arc<AuthState>,
+    resumed: Arc<Mutex<Option<Arc<Session>>>>,
+}
+
+impl Session {
+    /// `connection` is the number of the connection opening the session,
+    /// if the server is recording.
+    pub fn new<B: Backend>(
+        shared: &Shared<B>,
+        peer: SocketAddr,
+        connection: Option<u64>,
+    ) -> Arc<Self> {
+        let token = new_token();
+        let (notifications_tx, notifications_rx) = queue::queue(shared.queue);
+        let auth = AuthState::new(shared.authenticator.clone(), peer);
+        let resumed = Arc::new(Mutex::new(None));
+
+        // engine status and events are only for clients that may use the
+        // engine
+        let engine = shared.engine.clone();
+        let events = shared.events.clone();
+        let watcher = notifications_tx.clone();
+        auth.when_authenticated(move || {
+            engine.watch(watcher.clone());
+            events.watch(watcher);
+        });
+
+        let ids = GrammarIds::new(connection);
+        let protocol = Protocol::new(notifications_tx.clone());
+        let mut handler = create_handler(
+            shared,
+            notifications_tx,
+            protocol.clone(),
+            auth.clone(),
+            ids.clone(),
+        );
+        let rpc_session = RpcSessionImpl {
+            token: token.clone(),
+            sessions: shared.sessions.clone(),
+            resumed: resumed.clone(),
+            protocol: protocol.clone(),
+            ids,
+        };
+        handler.extend_with(rpc_session.to_delegate());
+
+        Arc::new(Session {
+            token,
+            handler,
+            notifications: Mutex::new(notifications_rx),
+            protocol,
+            auth,
+            resumed,
+        })
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.auth.is_rejected()
+    }
+
+    /// Serializes a notification that bypasses the queue.
+    pub fn render(&self, item: &Outgoing) -> Result<String> {
+        lock(&self.notifications).render(item)
+    }
+
+    fn take_resumed(&self) -> Option<Arc<Session>> {
+        lock(&self.resumed).take()
+    }
+}
+
+struct RegistryState {
+    generation: u64,
+    detached: HashMap<String, (u64, Arc<Session>)>,
+}
+
+/// Holds on to the sessions of disconnected clients until they either
+/// resume them or the grace period runs out.
+pub struct SessionRegistry {
+    grace: Duration,
+    state: Mutex<RegistryState>,
+}
+
+impl SessionRegistry {
+    /// A grace period of zero disables resumption altogether.
+    pub fn new(grace: Duration) -> Arc<Self> {
+        Arc::new(SessionRegistry {
+            grace,
+            state: Mutex::new(RegistryState {
+                generation: 0,
+                detached: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.grace != Duration::from_secs(0)
+    }
+
+    /// Keeps `session` around after its connection closed. It is dropped,
+    /// unloading its grammars, if nobody resumes it in time.
+    pub fn detach(self: &Arc<Self>, session: Arc<Session>, handle: &Handle) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let timeout = match Timeout::new(self.grace, handle) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                warn!("could not keep session: {}", e);
+                return;
+            }
+        };
+
+        let token = session.token.clone();
+        let generation = {
+            let mut state = lock(&self.state);
+            state.generation += 1;
+            let generation = state.generation;
+            state.detached.insert(token.clone(), (generation, session));
+            generation
+        };
+
+        let registry = self.clone();
+        handle.spawn(timeout.then(move |_| {
+            registry.expire(&token, generation);
+            Ok(())
+        }));
+    }
+
+    fn expire(&self, token: &str, generation: u64) {
+        let mut state = lock(&self.state);
+
+        // the session may have been resumed and detached again since
+        let current = state.detached.get(token).map(|&(g, _)| g);
+        if current == Some(generation) {
+            info!("session expired");
+            state.detached.remove(token);
+        }
+    }
+
+    /// Drops every detached session, unloading their grammars.
+    pub fn clear(&self) {
+        lock(&self.state).detached.clear();
+    }
+
+    /// Hands out the session with `token`, as long as it speaks protocol
+    /// `version`. Its notifications are laid out for that version already.
+    fn take(&self, token: &str, version: u64) -> Result<Arc<Session>> {
+        let mut state = lock(&self.state);
+
+        let resumed = match state.detached.get(token) {
+            Some(&(_, ref session)) => session.protocol.version(),
+            None => return Err(ErrorKind::UnknownSession.into()),
+        };
+        if resumed != version {
+            return Err(ErrorKind::ProtocolVersionFixed { version: resumed }.into());
+        }
+
+        Ok(state
+            .detached
+            .remove(token)
+            .map(|(_, session)| session)
+            .unwrap())
+    }
+}
+
+pub struct RpcSessionImpl {
+    token: String,
+    sessions: Arc<SessionRegistry>,
+    resumed: Arc<Mutex<Option<Arc<Session>>>>,
+    protocol: Arc<Protocol>,
+    ids: Arc<GrammarIds>,
+}
+
+impl RpcSession for RpcSessionImpl {
+    fn token(&self) -> Result<String> {
+        Ok(self.token.clone())
+    }
+
+    fn resume(&self, token: String) -> Result<()> {
+        // whatever was loaded on this connection would be dropped silently
+        if !self.ids.is_empty() {
+            return Err(ErrorKind::SessionNotEmpty.into());
+        }
+
+        let session = self.sessions.take(&token, self.protocol.version())?;
+        *lock(&self.resumed) = Some(session);
+        Ok(())
+    }
+}
+
+struct AttachmentState {
+    session: Arc<Session>,
+    task: Option<Task>,
+}
+
+/// The session a connection is currently serving. Requests are dispatched
+/// to its handler, and its notifications are available as a stream.
+#[derive(Clone)]
+pub struct Attachment(Arc<Mutex<AttachmentState>>);
+
+impl Attachment {
+    pub fn new(session: Arc<Session>) -> Self {
+        Attachment(Arc::new(Mutex::new(AttachmentState {
+            session,
+            task: None,
+        })))
+    }
+
+    pub fn session(&self) -> Arc<Session> {
+        lock(&self.0).session.clone()
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.session().is_rejected()
+    }
+
+    fn swap(&self, session: Arc<Session>) {
+        let mut state = lock(&self.0);
+        state.session = session;
+
+        // the stream was waiting on the notifications of the old session
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+    }
+
+    pub fn handle_request(&self, request: &str) -> impl Future<Item = Option<String>, Error = ()> {
+        let session = self.session();
+        let attachment = self.clone();
+
+        session
+            .handler
+            .handle_request(request, ())
+            .map(move |response| {
+                if let Some(resumed) = session.take_resumed() {
+                    info!("connection resumed an earlier session");
+                    attachment.swap(resumed);
+                }
+
+                response
+            })
+    }
+}
+
+impl Stream for Attachment {
+    type Item = String;
+    type Error = MyError;
+
+    fn poll(&mut self) -> Poll<Option<String>, MyError> {
+        let session = self.session();
+        let result = lock(&session.notifications).poll();
+
+        if let Ok(Async::NotReady) = result {
+            lock(&self.0).task = Some(task::current());
+        }
+
+        result
+    }
+}
@@ -0,0 +1,253 @@
+use crate::backend::*;
+use crate::errors::*;
+use crate::filter::{EventKind, Notifier};
+use crate::rpcimpl::{GrammarInfo, GrammarKind};
+use crate::validate;
+use log::{error, info};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use stentorian::engine::CommandGrammarEvent;
+use stentorian::grammar::Grammar;
+use stentorian::resultparser::Matcher;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+struct Subscribers {
+    counter: u64,
+    items: BTreeMap<u64, (u64, Notifier)>,
+}
+
+/// A command grammar loaded once for the whole server. It stays loaded
+/// for as long as any connection is attached to it.
+struct SharedGrammar<B: Backend> {
+    control: Mutex<B::CommandControl>,
+    info: Arc<Mutex<GrammarInfo>>,
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+/// The shared command grammars, by name.
+pub struct SharedGrammars<B: Backend> {
+    engine: Arc<B>,
+    grammars: Mutex<HashMap<String, Weak<SharedGrammar<B>>>>,
+}
+
+impl<B: Backend> SharedGrammars<B> {
+    pub fn new(engine: Arc<B>) -> Arc<Self> {
+        Arc::new(SharedGrammars {
+            engine,
+            grammars: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn find(
+        grammars: &mut HashMap<String, Weak<SharedGrammar<B>>>,
+        name: &str,
+    ) -> Option<Arc<SharedGrammar<B>>> {
+        grammars.retain(|_, g| g.upgrade().is_some());
+        grammars.get(name).and_then(|g| g.upgrade())
+    }
+
+    /// Attaches to the shared grammar called `name`, loading `grammar`
+    /// under that name first if there is no such grammar yet. Loading a
+    /// different grammar under a name that is taken fails.
+    ///
+    /// Recognitions are sent to `notifications` as
+    /// `command_grammar_notification`s for `id`. A grammar that is loaded
+    /// here is recorded under `label`.
+    pub fn load(
+        &self,
+        name: &str,
+        grammar: &Grammar,
+        id: u64,
+        label: Option<Label>,
+        notifications: Notifier,
+    ) -> Result<Attached<B>> {
+        let mut grammars = lock(&self.grammars);
+
+        if let Some(shared) = Self::find(&mut grammars, name) {
+            let existing = lock(&shared.info).grammar.clone();
+            if serde_json::to_value(&existing)? != serde_json::to_value(&Some(grammar))? {
+                let name = name.to_owned();
+                return Err(ErrorKind::SharedGrammarConflict { name }.into());
+            }
+
+            return Ok(Attached::new(shared, id, notifications));
+        }
+
+        let shared = self.create(name, grammar, label)?;
+        grammars.insert(name.to_owned(), Arc::downgrade(&shared));
+
+        Ok(Attached::new(shared, id, notifications))
+    }
+
+    /// Loads `grammar` under `name` for the server itself, with `rules`
+    /// active. It stays loaded until the returned handle is dropped, even
+    /// when no connection is attached to it.
+    pub fn preload(&self, name: &str, grammar: &Grammar, rules: &[String]) -> Result<Preloaded<B>> {
+        let mut grammars = lock(&self.grammars);
+
+        if Self::find(&mut grammars, name).is_some() {
+            let name = name.to_owned();
+            return Err(ErrorKind::SharedGrammarConflict { name }.into());
+        }
+
+        let shared = self.create(name, grammar, None)?;
+        for rule in rules {
+            validate::rule(grammar, rule)?;
+            lock(&shared.control).rule_activate(rule)?;
+        }
+
+        {
+            let mut info = lock(&shared.info);
+            info.active_rules = rules.iter().cloned().collect();
+            info.active = !rules.is_empty();
+        }
+
+        grammars.insert(name.to_owned(), Arc::downgrade(&shared));
+        Ok(Preloaded(shared))
+    }
+
+    /// Loads a shared grammar, which sends its recognitions to every
+    /// connection attached to it. While there are none, they are only
+    /// logged.
+    fn create(
+        &self,
+        name: &str,
+        grammar: &Grammar,
+        label: Option<Label>,
+    ) -> Result<Arc<SharedGrammar<B>>> {
+        let subscribers = Arc::new(Mutex::new(Subscribers {
+            counter: 0,
+            items: BTreeMap::new(),
+        }));
+        let matcher = Matcher::new(grammar);
+        let callback_subscribers = subscribers.clone();
+        let logged_name = name.to_owned();
+
+        let callback = move |e: CommandGrammarEvent| {
+            let with_matches = e.map(|words| {
+                let matches = matcher.perform_match(&words);
+                (words, matches)
+            });
+
+            let kind = EventKind::of(&with_matches);
+            let subscribers = lock(&callback_subscribers);
+
+            if subscribers.items.is_empty() {
+                match serde_json::to_string(&with_matches) {
+                    Ok(event) => info!("shared grammar {}: {}", logged_name, event),
+                    Err(e) => error!("{}", e),
+                }
+            }
+
+            for (id, notifications) in subscribers.items.values() {
+                notifications.notify(*id, "command_grammar_notification", kind, &with_matches);
+            }
+        };
+
+        let control = self.engine.command_grammar_load(grammar, label, callback)?;
+        let lost_subscribers = subscribers.clone();
+        control.when_lost(Box::new(move |e| {
+            for (id, notifications) in lock(&lost_subscribers).items.values() {
+                notifications.grammar_lost(*id, e);
+            }
+        }));
+
+        let mut info = GrammarInfo::new(0, GrammarKind::Command);
+        info.name = Some(name.to_owned());
+        info.shared = true;
+        info.grammar = Some(grammar.clone());
+
+        Ok(Arc::new(SharedGrammar {
+            control: Mutex::new(control),
+            info: Arc::new(Mutex::new(info)),
+            subscribers,
+        }))
+    }
+
+    /// Attaches to the shared grammar called `name`, which has to exist.
+    pub fn attach(&self, name: &str, id: u64, notifications: Notifier) -> Result<Attached<B>> {
+        match Self::find(&mut lock(&self.grammars), name) {
+            Some(shared) => Ok(Attached::new(shared, id, notifications)),
+            None => {
+                let name = name.to_owned();
+                Err(ErrorKind::UnknownSharedGrammar { name }.into())
+            }
+        }
+    }
+
+    /// The names of the shared grammars, in alphabetical order.
+    pub fn names(&self) -> Vec<String> {
+        let mut grammars = lock(&self.grammars);
+        grammars.retain(|_, g| g.upgrade().is_some());
+
+        let mut names: Vec<String> = grammars.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// The server's own hold on a shared grammar it loaded from the
+/// configuration file.
+pub struct Preloaded<B: Backend>(Arc<SharedGrammar<B>>);
+
+/// A connection's hold on a shared grammar. The grammar is unloaded when
+/// the last one is dropped.
+pub struct Attached<B: Backend> {
+    grammar: Arc<SharedGrammar<B>>,
+    key: u64,
+}
+
+impl<B: Backend> Attached<B> {
+    fn new(grammar: Arc<SharedGrammar<B>>, id: u64, notifications: Notifier) -> Self {
+        let key = {
+            let mut subscribers = lock(&grammar.subscribers);
+            subscribers.counter += 1;
+            let key = subscribers.counter;
+            subscribers.items.insert(key, (id, notifications));
+            key
+        };
+
+        Attached { grammar, key }
+    }
+
+    /// What is known about the grammar. Every connection attached to it
+    /// sees the same information.
+    pub fn info(&self) -> Arc<Mutex<GrammarInfo>> {
+        self.grammar.info.clone()
+    }
+
+    fn control(&self) -> MutexGuard<B::CommandControl> {
+        lock(&self.grammar.control)
+    }
+}
+
+impl<B: Backend> Drop for Attached<B> {
+    fn drop(&mut self) {
+        lock(&self.grammar.subscribers).items.remove(&self.key);
+    }
+}
+
+impl<B: Backend> CommandControl for Attached<B> {
+    fn rule_activate(&self, name: &str) -> Result<()> {
+        self.control().rule_activate(name)
+    }
+
+    fn rule_deactivate(&self, name: &str) -> Result<()> {
+        self.control().rule_deactivate(name)
+    }
+
+    fn list_append(&self, name: &str, word: &str) -> Result<()> {
+        self.control().list_append(name, word)
+    }
+
+    fn list_remove(&self, name: &str, word: &str) -> Result<()> {
+        self.control().list_remove(name, word)
+    }
+
+    fn list_clear(&self, name: &str) -> Result<()> {
+        self.control().list_clear(name)
+    }
+}
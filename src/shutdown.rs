@@ -0,0 +1,214 @@
+use crate::errors::*;
+use crate::rpc::RpcAdmin;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use log::info;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Why the server is going down.
+#[derive(Debug, Clone)]
+pub enum ShutdownReason {
+    Signal,
+    Request { peer: SocketAddr },
+}
+
+impl ShutdownReason {
+    pub fn describe(&self) -> String {
+        match *self {
+            ShutdownReason::Signal => "interrupted".to_owned(),
+            ShutdownReason::Request { ref peer } => format!("requested by {}", peer),
+        }
+    }
+}
+
+/// How the server stopped, which decides its exit code. Failures exit
+/// with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    /// Every connection closed, or there were none to close.
+    Clean,
+    /// Some connections were still open when the shutdown timeout ran out.
+    Forced,
+}
+
+impl Stopped {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Stopped::Clean => 0,
+            Stopped::Forced => 2,
+        }
+    }
+}
+
+struct State {
+    reason: Option<ShutdownReason>,
+    connections: usize,
+    next_waiter: u64,
+    /// The task to wake for each waiting future, which only keeps the one
+    /// it was last polled from.
+    tasks: BTreeMap<u64, Task>,
+}
+
+impl State {
+    fn wake(&self) {
+        for task in self.tasks.values() {
+            task.notify();
+        }
+    }
+}
+
+/// Coordinates a graceful shutdown: it can be triggered from anywhere, and
+/// keeps count of the connections that still have to close.
+pub struct Shutdown {
+    state: Mutex<State>,
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Shutdown {
+            state: Mutex::new(State {
+                reason: None,
+                connections: 0,
+                next_waiter: 0,
+                tasks: BTreeMap::new(),
+            }),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<State> {
+        self.state.lock().expect("attempt to lock poisoned mutex")
+    }
+
+    /// Starts shutting down. Only the first reason is kept.
+    pub fn trigger(&self, reason: ShutdownReason) {
+        let mut state = self.state();
+
+        if state.reason.is_none() {
+            info!("shutting down: {}", reason.describe());
+            state.reason = Some(reason);
+            state.wake();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.state().reason.is_some()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub fn triggered(self: &Arc<Self>) -> Triggered {
+        Triggered(Waiter::new(self))
+    }
+
+    /// Counts a connection as open until the guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.state().connections += 1;
+        ConnectionGuard(self.clone())
+    }
+
+    /// Resolves once every connection has closed.
+    pub fn drained(self: &Arc<Self>) -> Drained {
+        Drained(Waiter::new(self))
+    }
+}
+
+/// The part of a future that waits on the shutdown state.
+struct Waiter {
+    shutdown: Arc<Shutdown>,
+    key: u64,
+}
+
+impl Waiter {
+    fn new(shutdown: &Arc<Shutdown>) -> Self {
+        let mut state = shutdown.state();
+        state.next_waiter += 1;
+
+        Waiter {
+            shutdown: shutdown.clone(),
+            key: state.next_waiter,
+        }
+    }
+
+    /// Wakes the current task on the next change.
+    fn park(&self, state: &mut State) {
+        state.tasks.insert(self.key, task::current());
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.shutdown.state().tasks.remove(&self.key);
+    }
+}
+
+pub struct Triggered(Waiter);
+
+impl Future for Triggered {
+    type Item = ShutdownReason;
+    type Error = MyError;
+
+    fn poll(&mut self) -> Poll<ShutdownReason, MyError> {
+        let mut state = self.0.shutdown.state();
+
+        match state.reason {
+            Some(ref reason) => Ok(Async::Ready(reason.clone())),
+            None => {
+                self.0.park(&mut state);
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+pub struct Drained(Waiter);
+
+impl Future for Drained {
+    type Item = ();
+    type Error = MyError;
+
+    fn poll(&mut self) -> Poll<(), MyError> {
+        let mut state = self.0.shutdown.state();
+
+        if state.connections == 0 {
+            Ok(Async::Ready(()))
+        } else {
+            self.0.park(&mut state);
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state();
+        state.connections -= 1;
+
+        if state.connections == 0 {
+            state.wake();
+        }
+    }
+}
+
+pub struct RpcAdminImpl {
+    pub shutdown: Arc<Shutdown>,
+    pub allowed: bool,
+    pub peer: SocketAddr,
+}
+
+impl RpcAdmin for RpcAdminImpl {
+    fn shutdown(&self) -> Result<()> {
+        if !self.allowed {
+            return Err(ErrorKind::Forbidden {
+                reason: "shutting down through the API is disabled".to_owned(),
+            }
+            .into());
+        }
+
+        self.shutdown
+            .trigger(ShutdownReason::Request { peer: self.peer });
+        Ok(())
+    }
+}
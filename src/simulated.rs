@@ -0,0 +1,364 @@
+use crate::backend::*;
+use crate::errors::Result;
+use crate::mimic::{MimicRegistry, Mimicked};
+use crate::validate;
+use failure::format_err;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use stentorian::engine::{
+    CatchallGrammarEvent, CommandGrammarEvent, DictationGrammarEvent, GrammarEvent,
+    MicrophoneState, SelectGrammarEvent,
+};
+use stentorian::grammar::Grammar;
+
+type EngineCallback = Arc<dyn Fn(EngineEvent<u64>) + Sync + Send>;
+
+struct SharedState {
+    counter: u64,
+    pauses: u64,
+    microphone: MicrophoneState,
+    user: Option<String>,
+    registrations: HashMap<u64, EngineCallback>,
+}
+
+/// In-process stand-in for Dragon. It keeps track of the state clients set
+/// through the controls, but only recognizes what is passed to `mimic`.
+/// Like Dragon, it pauses before every utterance, but it does not wait to
+/// be resumed. Clones share their state.
+#[derive(Clone)]
+pub struct SimulatedEngine {
+    shared: Arc<Mutex<SharedState>>,
+    registry: Arc<MimicRegistry>,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<T> {
+    m.lock().expect("attempt to lock poisoned mutex")
+}
+
+impl SimulatedEngine {
+    pub fn new() -> Self {
+        let shared = SharedState {
+            counter: 0,
+            pauses: 0,
+            microphone: MicrophoneState::Off,
+            user: Some("simulated".to_owned()),
+            registrations: HashMap::new(),
+        };
+
+        SimulatedEngine {
+            shared: Arc::new(Mutex::new(shared)),
+            registry: MimicRegistry::new(),
+        }
+    }
+
+    /// Delivers `event` to the `key`th grammar loaded, see
+    /// `MimicRegistry::inject`.
+    pub fn inject(&self, key: u64, event: GrammarEvent<Vec<String>>) -> bool {
+        self.registry.inject(key, event)
+    }
+
+    /// Switches to another user, as if it had been picked in the engine.
+    pub fn user_set(&self, user: Option<String>) {
+        lock(&self.shared).user = user;
+        self.broadcast(EngineEvent::UserChanged);
+    }
+
+    /// Pauses the engine, as it does at the start of an utterance.
+    pub fn pause(&self) {
+        let cookie = {
+            let mut shared = lock(&self.shared);
+            shared.pauses += 1;
+            shared.pauses
+        };
+
+        self.broadcast(EngineEvent::Paused(cookie));
+    }
+
+    fn broadcast(&self, event: EngineEvent<u64>) {
+        // collect the callbacks first, since they are allowed to call back
+        // into the engine
+        let callbacks: Vec<EngineCallback> =
+            lock(&self.shared).registrations.values().cloned().collect();
+
+        for callback in callbacks {
+            callback(event.clone());
+        }
+    }
+}
+
+impl Default for SimulatedEngine {
+    fn default() -> Self {
+        SimulatedEngine::new()
+    }
+}
+
+impl Backend for SimulatedEngine {
+    type CommandControl = Mimicked<SimulatedCommandControl>;
+    type SelectControl = Mimicked<SimulatedSelectControl>;
+    type DictationControl = Mimicked<SimulatedDictationControl>;
+    type CatchallControl = Mimicked<SimulatedCatchallControl>;
+    type Registration = SimulatedRegistration;
+    type PauseCookie = u64;
+
+    fn command_grammar_load<F>(
+        &self,
+        grammar: &Grammar,
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CommandControl>
+    where
+        F: Fn(CommandGrammarEvent) + Sync + Send + 'static,
+    {
+        validate::grammar(grammar)?;
+
+        self.registry.command_grammar_load(grammar, callback, |_| {
+            Ok(SimulatedCommandControl {
+                grammar: grammar.clone(),
+                active_rules: Mutex::new(HashSet::new()),
+                lists: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    fn select_grammar_load<F>(
+        &self,
+        select_words: &[String],
+        through_words: &[String],
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::SelectControl>
+    where
+        F: Fn(SelectGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry
+            .select_grammar_load(select_words, through_words, callback, |_| {
+                Ok(SimulatedSelectControl {
+                    active: Mutex::new(false),
+                    text: Mutex::new(String::new()),
+                })
+            })
+    }
+
+    fn dictation_grammar_load<F>(
+        &self,
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::DictationControl>
+    where
+        F: Fn(DictationGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry.dictation_grammar_load(callback, |_| {
+            Ok(SimulatedDictationControl {
+                active: Mutex::new(false),
+                context: Mutex::new(String::new()),
+            })
+        })
+    }
+
+    fn catchall_grammar_load<F>(
+        &self,
+        _label: Option<Label>,
+        callback: F,
+    ) -> Result<Self::CatchallControl>
+    where
+        F: Fn(CatchallGrammarEvent) + Sync + Send + 'static,
+    {
+        self.registry.catchall_grammar_load(callback, |_| {
+            Ok(SimulatedCatchallControl {
+                active: Mutex::new(false),
+            })
+        })
+    }
+
+    fn register<F>(&self, callback: F) -> Result<Self::Registration>
+    where
+        F: Fn(EngineEvent<u64>) + Sync + Send + 'static,
+    {
+        let mut shared = lock(&self.shared);
+        shared.counter += 1;
+        let id = shared.counter;
+        shared.registrations.insert(id, Arc::new(callback));
+
+        Ok(SimulatedRegistration {
+            id,
+            shared: Arc::downgrade(&self.shared),
+        })
+    }
+
+    fn resume(&self, _cookie: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn microphone_set_state(&self, state: MicrophoneState) -> Result<()> {
+        lock(&self.shared).microphone = state;
+        self.broadcast(EngineEvent::MicrophoneState);
+        Ok(())
+    }
+
+    fn microphone_get_state(&self) -> Result<MicrophoneState> {
+        Ok(lock(&self.shared).microphone)
+    }
+
+    fn get_current_user(&self) -> Result<Option<String>> {
+        Ok(lock(&self.shared).user.clone())
+    }
+
+    fn mimic(&self, words: &[String]) -> Result<bool> {
+        self.pause();
+        Ok(self.registry.mimic(words))
+    }
+}
+
+pub struct SimulatedRegistration {
+    id: u64,
+    shared: Weak<Mutex<SharedState>>,
+}
+
+impl Drop for SimulatedRegistration {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.upgrade() {
+            lock(&shared).registrations.remove(&self.id);
+        }
+    }
+}
+
+pub struct SimulatedCommandControl {
+    grammar: Grammar,
+    active_rules: Mutex<HashSet<String>>,
+    lists: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl CommandControl for SimulatedCommandControl {
+    fn rule_activate(&self, name: &str) -> Result<()> {
+        validate::rule(&self.grammar, name)?;
+        lock(&self.active_rules).insert(name.to_owned());
+        Ok(())
+    }
+
+    fn rule_deactivate(&self, name: &str) -> Result<()> {
+        validate::rule(&self.grammar, name)?;
+        lock(&self.active_rules).remove(name);
+        Ok(())
+    }
+
+    fn list_append(&self, name: &str, word: &str) -> Result<()> {
+        validate::list(&self.grammar, name)?;
+        lock(&self.lists)
+            .entry(name.to_owned())
+            .or_insert_with(Vec::new)
+            .push(word.to_owned());
+        Ok(())
+    }
+
+    fn list_remove(&self, name: &str, word: &str) -> Result<()> {
+        validate::list(&self.grammar, name)?;
+        // like Dragon, only remove one entry
+        if let Some(words) = lock(&self.lists).get_mut(name) {
+            if let Some(i) = words.iter().position(|w| w == word) {
+                words.remove(i);
+            }
+        }
+        Ok(())
+    }
+
+    fn list_clear(&self, name: &str) -> Result<()> {
+        validate::list(&self.grammar, name)?;
+        lock(&self.lists).remove(name);
+        Ok(())
+    }
+}
+
+pub struct SimulatedSelectControl {
+    active: Mutex<bool>,
+    text: Mutex<String>,
+}
+
+/// Converts a range of character offsets into a range of byte offsets.
+fn byte_range(text: &str, start: u32, stop: u32) -> Result<Range<usize>> {
+    let mut offsets = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()));
+    let start_byte = offsets.clone().nth(start as usize);
+    let stop_byte = offsets.nth(stop as usize);
+
+    match (start_byte, stop_byte) {
+        (Some(a), Some(b)) if a <= b => Ok(a..b),
+        _ => Err(format_err!("invalid text range {}..{}", start, stop).into()),
+    }
+}
+
+impl SelectControl for SimulatedSelectControl {
+    fn activate(&self) -> Result<()> {
+        *lock(&self.active) = true;
+        Ok(())
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        *lock(&self.active) = false;
+        Ok(())
+    }
+
+    fn text_set(&self, text: &str) -> Result<()> {
+        *lock(&self.text) = text.to_owned();
+        Ok(())
+    }
+
+    fn text_change(&self, start: u32, stop: u32, text: &str) -> Result<()> {
+        let mut current = lock(&self.text);
+        let range = byte_range(&current, start, stop)?;
+        current.replace_range(range, text);
+        Ok(())
+    }
+
+    fn text_delete(&self, start: u32, stop: u32) -> Result<()> {
+        self.text_change(start, stop, "")
+    }
+
+    fn text_insert(&self, start: u32, text: &str) -> Result<()> {
+        self.text_change(start, start, text)
+    }
+
+    fn text_get(&self) -> Result<String> {
+        Ok(lock(&self.text).clone())
+    }
+}
+
+pub struct SimulatedDictationControl {
+    active: Mutex<bool>,
+    context: Mutex<String>,
+}
+
+impl DictationControl for SimulatedDictationControl {
+    fn activate(&self) -> Result<()> {
+        *lock(&self.active) = true;
+        Ok(())
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        *lock(&self.active) = false;
+        Ok(())
+    }
+
+    fn context_set(&self, context: &str) -> Result<()> {
+        *lock(&self.context) = context.to_owned();
+        Ok(())
+    }
+}
+
+pub struct SimulatedCatchallControl {
+    active: Mutex<bool>,
+}
+
+impl CatchallControl for SimulatedCatchallControl {
+    fn activate(&self) -> Result<()> {
+        *lock(&self.active) = true;
+        Ok(())
+    }
+
+    fn deactivate(&self) -> Result<()> {
+        *lock(&self.active) = false;
+        Ok(())
+    }
+}
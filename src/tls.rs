@@ -0,0 +1,67 @@
+use crate::errors::*;
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+fn invalid(reason: String) -> MyError {
+    ErrorKind::InvalidConfig { reason }.into()
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = pemfile::certs(&mut reader)
+        .map_err(|()| invalid(format!("could not parse {}", path.display())))?;
+
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", path.display())));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let parse_error = || invalid(format!("could not parse {}", path.display()));
+
+    // the key may be stored in either of these formats
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).map_err(|()| parse_error())?;
+
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).map_err(|()| parse_error())?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid(format!("no private key in {}", path.display())))
+}
+
+/// Builds the acceptor used to wrap incoming TCP connections. When
+/// `client_ca` is given, clients have to present a certificate signed by
+/// one of the authorities in that file.
+pub fn load_acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let mut config = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(path)? {
+                roots.add(&ca).map_err(|e| {
+                    invalid(format!("bad certificate in {}: {}", path.display(), e))
+                })?;
+            }
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+
+    config
+        .set_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| invalid(format!("bad certificate or key: {}", e)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
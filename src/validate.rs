@@ -0,0 +1,86 @@
+//! Checks on command grammars made before anything reaches the engine, so
+//! every backend reports mistakes with the same error kinds. Dragon itself
+//! only answers with an HRESULT that does not say what was wrong.
+
+use crate::errors::*;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use stentorian::grammar::Grammar;
+
+fn invalid(reason: String) -> MyError {
+    ErrorKind::InvalidGrammar { reason }.into()
+}
+
+fn collect(value: &Value, tag: &str, names: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(fields) => {
+            if fields.get("type").and_then(Value::as_str) == Some(tag) {
+                if let Some(name) = fields.get("name").and_then(Value::as_str) {
+                    names.insert(name.to_owned());
+                }
+            }
+
+            for field in fields.values() {
+                collect(field, tag, names);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect(item, tag, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The names used by elements of type `tag`, taken from the serialized
+/// grammar so they match what clients sent.
+fn references(grammar: &Grammar, tag: &str) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    collect(&serde_json::to_value(grammar)?, tag, &mut names);
+    Ok(names)
+}
+
+/// Rejects grammars with duplicate rules, references to rules that do not
+/// exist, or nothing to recognize.
+pub fn grammar(grammar: &Grammar) -> Result<()> {
+    let mut names = BTreeSet::new();
+
+    for rule in &grammar.rules {
+        if !names.insert(rule.name.as_str()) {
+            return Err(invalid(format!("duplicate rule {}", rule.name)));
+        }
+    }
+
+    for name in references(grammar, "rule_ref")? {
+        if !names.contains(name.as_str()) {
+            return Err(invalid(format!("reference to undefined rule {}", name)));
+        }
+    }
+
+    if !grammar.rules.iter().any(|r| r.exported) {
+        return Err(invalid("grammar has no exported rules".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Only exported rules can be activated.
+pub fn rule(grammar: &Grammar, name: &str) -> Result<()> {
+    if grammar.rules.iter().any(|r| r.exported && r.name == name) {
+        return Ok(());
+    }
+
+    let name = name.to_owned();
+    Err(ErrorKind::UnknownRule { name }.into())
+}
+
+/// Lists exist when some rule uses them.
+pub fn list(grammar: &Grammar, name: &str) -> Result<()> {
+    if references(grammar, "list")?.contains(name) {
+        return Ok(());
+    }
+
+    let name = name.to_owned();
+    Err(ErrorKind::UnknownList { name }.into())
+}
@@ -0,0 +1,96 @@
+use crate::backend::Backend;
+use crate::connection::{handle_connection, Shared};
+use crate::errors::*;
+use futures::{Future, Sink, Stream};
+use log::{error, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::write_all;
+use websocket::message::OwnedMessage;
+use websocket::r#async::{Client, Server};
+use websocket::result::WebSocketError;
+
+const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+fn serve_client<B: Backend>(
+    handle: &Handle,
+    shared: &Shared<B>,
+    peer: SocketAddr,
+    client: Client<TcpStream>,
+) {
+    let (sink, stream) = client.split();
+
+    let requests = stream
+        .take_while(|m| Ok(!m.is_close()))
+        .filter_map(|m| match m {
+            OwnedMessage::Text(s) => Some(s),
+            _ => None,
+        });
+    let responses =
+        sink.with(|s| -> ::std::result::Result<_, WebSocketError> { Ok(OwnedMessage::Text(s)) });
+
+    handle.spawn(handle_connection(shared, handle, peer, requests, responses));
+}
+
+/// Accepts WebSocket connections on `addr`. Every text frame carries a
+/// single JSON-RPC message, and responses and notifications are sent back
+/// as text frames.
+///
+/// Any web page open in a browser on this machine can reach the listener,
+/// so browsers are only let in from one of `origins`.
+pub fn listen<B: Backend>(
+    addr: &SocketAddr,
+    handle: &Handle,
+    shared: Arc<Shared<B>>,
+    origins: Vec<String>,
+) -> Result<impl Future<Item = (), Error = MyError>> {
+    let server = Server::bind(addr, handle)?;
+
+    info!("listening for WebSocket connections on {}", addr);
+
+    let handle = handle.clone();
+    let incoming = server
+        .incoming()
+        .then(|r| match r {
+            Ok(upgrade) => Ok(Some(upgrade)),
+            Err(e) => {
+                // a failed handshake only affects that one client
+                error!("invalid WebSocket connection: {}", e.error);
+                Ok(None)
+            }
+        })
+        .filter_map(|x| x);
+
+    let server = incoming.for_each(move |(upgrade, peer)| {
+        // browsers always send an origin, other clients need not
+        let origin = upgrade.origin().map(|o| o.to_owned());
+        if let Some(origin) = origin {
+            if !origins.contains(&origin) {
+                warn!("refused WebSocket connection from {} for {}", peer, origin);
+                handle.spawn(write_all(upgrade.stream, FORBIDDEN).then(|_| Ok(())));
+                return Ok(());
+            }
+        }
+
+        info!("new WebSocket connection from {}", peer);
+        let shared = shared.clone();
+        let spawn_handle = handle.clone();
+
+        let accept = upgrade.accept().then(move |r| {
+            match r {
+                Ok((client, _)) => serve_client(&spawn_handle, &shared, peer, client),
+                Err(e) => error!("WebSocket handshake failed: {}", e),
+            }
+
+            Ok(())
+        });
+
+        handle.spawn(accept);
+        Ok(())
+    });
+
+    Ok(server)
+}
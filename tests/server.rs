@@ -0,0 +1,540 @@
+//! Runs the server against the simulated engine and talks JSON-RPC to it
+//! over TCP, the way a client would.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Server {
+    child: Child,
+    port: u16,
+    config: PathBuf,
+}
+
+impl Server {
+    fn start(args: &[&str]) -> Self {
+        // an empty file keeps a configuration in the user's directory from
+        // getting in the way
+        Server::with_config("", args)
+    }
+
+    fn with_config(contents: &str, args: &[&str]) -> Self {
+        let port = free_port();
+        let config = env::temp_dir().join(format!("stentorian-test-{}.toml", port));
+        fs::write(&config, contents).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_stentorian-server"))
+            .arg("--config")
+            .arg(&config)
+            .arg("--port")
+            .arg(port.to_string())
+            .args(args)
+            .spawn()
+            .unwrap();
+
+        Server {
+            child,
+            port,
+            config,
+        }
+    }
+
+    /// Connects once the server is listening and the engine is up.
+    fn connect(&self) -> Connection {
+        let started = Instant::now();
+
+        loop {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", self.port)) {
+                let mut connection = Connection::new(stream);
+                if connection.result("engine_status", json!([])) == json!("connected") {
+                    return connection;
+                }
+            }
+
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "server did not start");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// The exit code, once the server has stopped by itself.
+    fn exit_code(&mut self) -> Option<i32> {
+        let started = Instant::now();
+
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status.code();
+            }
+
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "server did not stop");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.config);
+    }
+}
+
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+    notifications: Vec<Value>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        Connection {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            next_id: 0,
+            notifications: Vec::new(),
+        }
+    }
+
+    fn read(&mut self) -> Value {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    /// The response to the call, keeping the notifications that arrive
+    /// before it.
+    fn call(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        writeln!(self.writer, "{}", request).unwrap();
+
+        loop {
+            let message = self.read();
+            if message["id"] == json!(id) {
+                return message;
+            }
+
+            self.notifications.push(message);
+        }
+    }
+
+    fn result(&mut self, method: &str, params: Value) -> Value {
+        let response = self.call(method, params);
+        assert!(response.get("error").is_none(), "{}", response);
+        response["result"].clone()
+    }
+
+    fn error_code(&mut self, method: &str, params: Value) -> i64 {
+        let response = self.call(method, params);
+        response["error"]["code"]
+            .as_i64()
+            .unwrap_or_else(|| panic!("expected an error: {}", response))
+    }
+
+    fn notification(&mut self, method: &str) -> Value {
+        loop {
+            if let Some(i) = self
+                .notifications
+                .iter()
+                .position(|n| n["method"] == method)
+            {
+                return self.notifications.remove(i);
+            }
+
+            let message = self.read();
+            self.notifications.push(message);
+        }
+    }
+}
+
+fn greeting_grammar() -> Value {
+    json!({
+        "rules": [
+            {
+                "name": "greeting",
+                "exported": true,
+                "definition": { "type": "word", "text": "hello" },
+            },
+            {
+                "name": "farewell",
+                "exported": true,
+                "definition": { "type": "word", "text": "goodbye" },
+            },
+        ]
+    })
+}
+
+#[test]
+fn mimic_reaches_active_command_grammar() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    let id = connection.result("command_grammar_load", json!([greeting_grammar()]));
+    connection.result("command_grammar_rule_activate", json!([id, "greeting"]));
+
+    assert_eq!(
+        connection.result("engine_mimic", json!([["hello"]])),
+        json!(true)
+    );
+    let notification = connection.notification("command_grammar_notification");
+    assert_eq!(notification["params"][0], id);
+}
+
+#[test]
+fn mimic_ignores_inactive_rules() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    let id = connection.result("command_grammar_load", json!([greeting_grammar()]));
+    connection.result("command_grammar_rule_activate", json!([id, "greeting"]));
+
+    assert_eq!(
+        connection.result("engine_mimic", json!([["goodbye"]])),
+        json!(false)
+    );
+}
+
+#[test]
+fn mimic_matches_select_text() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    let id = connection.result("select_grammar_load", json!([["select"], ["through"]]));
+    connection.result("select_grammar_activate", json!([id]));
+    connection.result(
+        "select_grammar_text_set",
+        json!([id, "the quick brown fox"]),
+    );
+
+    let mimic = |connection: &mut Connection, words: &[&str]| {
+        connection.result("engine_mimic", json!([words]))
+    };
+    assert_eq!(
+        mimic(&mut connection, &["select", "quick", "brown"]),
+        json!(true)
+    );
+    assert_eq!(
+        mimic(&mut connection, &["select", "quick", "through", "fox"]),
+        json!(true)
+    );
+    assert_eq!(mimic(&mut connection, &["select", "slow"]), json!(false));
+    assert_eq!(
+        mimic(&mut connection, &["select", "fox", "brown"]),
+        json!(false)
+    );
+}
+
+#[test]
+fn errors_have_stable_codes() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    assert_eq!(
+        connection.error_code("command_grammar_unload", json!([42])),
+        -32001
+    );
+
+    let no_exports = json!({
+        "rules": [{
+            "name": "hidden",
+            "exported": false,
+            "definition": { "type": "word", "text": "hello" },
+        }]
+    });
+    assert_eq!(
+        connection.error_code("command_grammar_load", json!([no_exports])),
+        -32002
+    );
+
+    let id = connection.result("command_grammar_load", json!([greeting_grammar()]));
+    assert_eq!(
+        connection.error_code("command_grammar_rule_activate", json!([id, "missing"])),
+        -32003
+    );
+    assert_eq!(
+        connection.error_code("select_grammar_activate", json!([id])),
+        -32007
+    );
+    assert_eq!(
+        connection.error_code(
+            "command_grammar_list_append",
+            json!([id, "missing", "word"])
+        ),
+        -32004
+    );
+}
+
+#[test]
+fn resume_refuses_connections_with_grammars() {
+    let server = Server::start(&["--session-grace", "60"]);
+    let mut first = server.connect();
+    let token = first.result("session_token", json!([]));
+    drop(first);
+
+    let mut second = server.connect();
+    second.result("command_grammar_load", json!([greeting_grammar()]));
+    assert_eq!(second.error_code("session_resume", json!([token])), -32018);
+}
+
+#[test]
+fn list_get_reports_unknown_lists() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    let grammar = json!({
+        "rules": [{
+            "name": "call",
+            "exported": true,
+            "definition": { "type": "list", "name": "people" },
+        }]
+    });
+    let id = connection.result("command_grammar_load", json!([grammar]));
+    connection.result(
+        "command_grammar_list_append",
+        json!([id, "people", "alice"]),
+    );
+
+    assert_eq!(
+        connection.result("list_get", json!([id, "people"])),
+        json!(["alice"])
+    );
+    assert_eq!(
+        connection.error_code("list_get", json!([id, "places"])),
+        -32004
+    );
+    assert_eq!(connection.error_code("grammar_get", json!([42])), -32001);
+}
+
+#[test]
+fn discover_documents_optional_params() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    let document = connection.result("rpc.discover", json!([]));
+    let load = document["methods"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|m| m["name"] == "command_grammar_load")
+        .unwrap()
+        .clone();
+    assert_eq!(load["params"][0]["name"], "grammar");
+    assert_eq!(load["params"][0]["required"], true);
+    assert_eq!(load["params"][1]["name"], "name");
+    assert_eq!(load["params"][1]["required"], false);
+    assert!(document["components"]["schemas"]["Grammar"].is_object());
+}
+
+#[test]
+fn preloaded_grammars_can_be_attached() {
+    let grammar = env::temp_dir().join(format!("stentorian-test-{}.json", free_port()));
+    fs::write(&grammar, greeting_grammar().to_string()).unwrap();
+    let config = format!(
+        "[[grammars]]\nfile = {:?}\nname = \"global\"\nrules = [\"greeting\"]\n",
+        grammar.display().to_string()
+    );
+
+    let server = Server::with_config(&config, &[]);
+    let mut connection = server.connect();
+    fs::remove_file(&grammar).unwrap();
+
+    assert_eq!(
+        connection.result("command_grammar_list_shared", json!([])),
+        json!(["global"])
+    );
+    let id = connection.result("command_grammar_attach", json!(["global"]));
+    let grammars = connection.result("list_grammars", json!([]));
+    assert_eq!(grammars[0]["id"], id);
+    assert_eq!(grammars[0]["name"], "global");
+
+    assert_eq!(
+        connection.result("engine_mimic", json!([["hello"]])),
+        json!(true)
+    );
+    let notification = connection.notification("command_grammar_notification");
+    assert_eq!(notification["params"][0], id);
+}
+
+#[test]
+fn shutdown_exits_cleanly_once_connections_close() {
+    let mut server = Server::start(&["--allow-shutdown"]);
+    let mut connection = server.connect();
+
+    connection.result("server_shutdown", json!([]));
+    drop(connection);
+
+    assert_eq!(server.exit_code(), Some(0));
+}
+
+#[test]
+fn protocol_version_is_chosen_once_per_session() {
+    let server = Server::start(&["--session-grace", "60"]);
+    // engine status notifications may be queued by now, which does not
+    // keep the client from choosing
+    let mut first = server.connect();
+    first.result("protocol_version", json!([2]));
+    assert_eq!(first.error_code("protocol_version", json!([1])), -32020);
+    let token = first.result("session_token", json!([]));
+    drop(first);
+
+    // the session is only kept once the server noticed the connection close
+    let mut second = server.connect();
+    let started = Instant::now();
+    loop {
+        match second.error_code("session_resume", json!([token])) {
+            -32012 => assert!(started.elapsed() < STARTUP_TIMEOUT, "session was not kept"),
+            code => {
+                assert_eq!(code, -32020);
+                break;
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    second.result("hello", json!([2]));
+    second.result("session_resume", json!([token]));
+}
+
+#[test]
+fn protocol_versions_lay_out_notifications() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+    let versions = connection.result("hello", json!([]))["protocol_versions"].clone();
+    assert_eq!(versions, json!([1, 2]));
+
+    for version in versions.as_array().unwrap() {
+        let mut connection = server.connect();
+        connection.result("hello", json!([version]));
+
+        let id = connection.result("command_grammar_load", json!([greeting_grammar()]));
+        connection.result("command_grammar_rule_activate", json!([id, "greeting"]));
+        connection.result("engine_mimic", json!([["hello"]]));
+
+        let notification = connection.notification("command_grammar_notification");
+        let params = &notification["params"];
+        match version.as_u64().unwrap() {
+            1 => {
+                assert_eq!(params[0], id);
+                assert_eq!(params.as_array().unwrap().len(), 2);
+            }
+            _ => {
+                assert_eq!(params["grammar_id"], id);
+                assert!(params["seq"].is_u64());
+            }
+        }
+    }
+}
+
+#[test]
+fn websocket_requires_a_token() {
+    let port = free_port().to_string();
+    let mut server = Server::start(&["--ws-port", &port]);
+    assert_eq!(server.exit_code(), Some(1));
+}
+
+#[test]
+fn websocket_refuses_foreign_origins() {
+    let token = env::temp_dir().join(format!("stentorian-test-{}.token", free_port()));
+    fs::write(&token, "secret").unwrap();
+    let port = free_port();
+    let token_arg = token.display().to_string();
+    let _server = Server::start(&["--ws-port", &port.to_string(), "--token-file", &token_arg]);
+
+    let started = Instant::now();
+    let mut stream = loop {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+            break stream;
+        }
+
+        assert!(started.elapsed() < STARTUP_TIMEOUT, "server did not start");
+        thread::sleep(Duration::from_millis(50));
+    };
+    fs::remove_file(&token).unwrap();
+
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: 127.0.0.1:{}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Origin: http://example.com\r\n\r\n",
+        port
+    )
+    .unwrap();
+
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).unwrap();
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+}
+
+#[test]
+fn list_remove_removes_one_entry() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+
+    let grammar = json!({
+        "rules": [{
+            "name": "call",
+            "exported": true,
+            "definition": { "type": "list", "name": "people" },
+        }]
+    });
+    let id = connection.result("command_grammar_load", json!([grammar]));
+    for word in &["alice", "bob", "alice"] {
+        connection.result("command_grammar_list_append", json!([id, "people", word]));
+    }
+    connection.result(
+        "command_grammar_list_remove",
+        json!([id, "people", "alice"]),
+    );
+
+    assert_eq!(
+        connection.result("list_get", json!([id, "people"])),
+        json!(["bob", "alice"])
+    );
+}
+
+#[test]
+fn engine_events_reach_every_connection() {
+    let server = Server::start(&[]);
+    let mut listener = server.connect();
+    let mut registered = server.connect();
+    let id = registered.result("engine_register", json!([]));
+
+    registered.result("microphone_set_state", json!(["on"]));
+
+    let event = listener.notification("engine_event");
+    assert_eq!(event["params"][0]["type"], "microphone_state_changed");
+    assert_eq!(event["params"][0]["state"], "on");
+
+    let notification = registered.notification("engine_notification");
+    assert_eq!(notification["params"][0], id);
+    registered.result("engine_status", json!([]));
+    assert!(registered
+        .notifications
+        .iter()
+        .all(|n| n["method"] != "engine_event"));
+}